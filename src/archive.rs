@@ -0,0 +1,321 @@
+//! A JSON-lines manifest of every image rustwari saves, so finding a particular disc doesn't mean
+//! trawling through filenames.
+
+use crate::cvutils::get_dims;
+use crate::filenames::{frame_path, FilenameTemplate};
+use crate::fileutils::{images_in, AtomicFile};
+use crate::himawaridt::{HimawariDatetime, SOURCE};
+use crate::sources::TileSource;
+use crate::user_config::Config;
+
+use anyhow::Result;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// The manifest's name, it lives in the `backup` dir unless `archive:` is set in the config.yml
+pub const MANIFEST: &str = "archive.jsonl";
+
+/// A single saved image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchiveEntry {
+    /// YYYY-MM-DD HH:MM (UTC)
+    pub timestamp: String,
+    pub source: String,
+    pub product: String,
+    pub width: u32,
+    pub height: u32,
+    pub path: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl ArchiveEntry {
+    /// Build an entry by inspecting a saved image, the timestamp comes from its path, as laid out
+    /// by `template` (or, failing that, the name we've always used).
+    pub fn from_file<P: AsRef<Path>>(
+        p: P,
+        source: &TileSource,
+        template: &FilenameTemplate,
+    ) -> Result<Self> {
+        let path = std::fs::canonicalize(p)?;
+        let hwdt = match template.parse(&path) {
+            Ok(name) => name.hwdt,
+            Err(_) => HimawariDatetime::from_path(&path)?,
+        };
+        let (width, height) = get_dims(&path)?;
+
+        Ok(Self {
+            timestamp: hwdt.to_string(),
+            source: source.name.clone(),
+            product: source.product.clone(),
+            width,
+            height,
+            size: std::fs::metadata(&path)?.len(),
+            sha256: checksum(&path)?,
+            path,
+        })
+    }
+}
+
+/// What's wrong with an entry, as found by [`Archive::verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    Missing,
+    ChecksumMismatch { actual: String },
+}
+
+/// The manifest on disk.
+#[derive(Debug, Clone)]
+pub struct Archive {
+    pub path: PathBuf,
+    /// Where the images being recorded came from.
+    pub source: TileSource,
+    /// How they're named.
+    pub template: FilenameTemplate,
+}
+
+impl Archive {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            source: TileSource::default(),
+            template: FilenameTemplate::default(),
+        }
+    }
+
+    /// The archive configured in the user's config.yml
+    pub fn from_config(uc: &Config) -> Self {
+        let mut archive = match &uc.archive {
+            Some(p) => Self::new(p),
+            None => Self::new(Path::new(&uc.backup).join(MANIFEST)),
+        };
+        archive.template = uc.filename.clone();
+        match TileSource::from_config(uc) {
+            Ok(source) => archive.source = source,
+            Err(e) => warn!("{e}, archiving as {SOURCE}"),
+        }
+        archive
+    }
+
+    /// Everything in the manifest, oldest first. Should a path appear more than once the most
+    /// recent record of it wins.
+    pub fn entries(&self) -> Result<Vec<ArchiveEntry>> {
+        if !self.path.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut entries: Vec<ArchiveEntry> = vec![];
+        // Where each path's entry is in `entries`, so a later record replaces it in place.
+        let mut seen: HashMap<PathBuf, usize> = HashMap::new();
+        for (n, line) in BufReader::new(File::open(&self.path)?).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<ArchiveEntry>(&line) {
+                Ok(e) => match seen.get(&e.path) {
+                    Some(&i) => entries[i] = e,
+                    None => {
+                        seen.insert(e.path.clone(), entries.len());
+                        entries.push(e);
+                    }
+                },
+                Err(e) => warn!("{}:{} is unreadable: {e}", self.path.display(), n + 1),
+            }
+        }
+        entries.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        Ok(entries)
+    }
+
+    /// Appends `entry` to the manifest.
+    pub fn record(&self, entry: &ArchiveEntry) -> Result<()> {
+        let mut fd = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(fd, "{}", serde_json::to_string(entry)?)?;
+        debug!("Archived: {}", entry.path.display());
+        Ok(())
+    }
+
+    /// Records the image at `p`, failures are logged rather than returned as the image itself is fine.
+    pub fn record_file<P: AsRef<Path>>(&self, p: P) {
+        if let Err(e) = ArchiveEntry::from_file(&p, &self.source, &self.template)
+            .and_then(|entry| self.record(&entry))
+        {
+            warn!("Unable to archive {}: {e}", p.as_ref().display());
+        }
+    }
+
+    /// Drops any entries for `paths`, used when images are pruned (so they're likely gone already).
+    pub fn forget(&self, paths: &[PathBuf]) -> Result<()> {
+        let paths: HashSet<PathBuf> = paths.iter().map(|p| key(p)).collect();
+        let entries = self.entries()?;
+        if entries.iter().any(|e| paths.contains(&e.path)) {
+            self.rewrite(entries.iter().filter(|e| !paths.contains(&e.path)))?;
+        }
+        Ok(())
+    }
+
+    /// The disc for `hwdt` if we've got it, from the archive, `completed` or `backup`.
+    pub fn local_disc(&self, uc: &Config, hwdt: HimawariDatetime) -> Result<Option<PathBuf>> {
+        Ok(self
+            .find(&hwdt.to_string())?
+            .into_iter()
+            .map(|e| e.path)
+            .chain([
+                frame_path(&uc.completed, uc, hwdt),
+                frame_path(&uc.backup, uc, hwdt),
+            ])
+            .find(|p| p.is_file()))
+    }
+
+    /// Entries whose timestamp begins with `query`, i.e `2022-09` or `2022-09-21 00:10`.
+    pub fn find(&self, query: &str) -> Result<Vec<ArchiveEntry>> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .filter(|e| e.timestamp.starts_with(query))
+            .collect())
+    }
+
+    /// Check every entry's file is still there, and still what we saved.
+    pub fn verify(&self) -> Result<Vec<(ArchiveEntry, Problem)>> {
+        let mut problems = vec![];
+        for e in self.entries()? {
+            if !e.path.is_file() {
+                problems.push((e, Problem::Missing));
+                continue;
+            }
+            let actual = checksum(&e.path)?;
+            if actual != e.sha256 {
+                problems.push((e, Problem::ChecksumMismatch { actual }));
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Throw the manifest away and rebuild it from the images in `dirs`.
+    pub fn reindex<P: AsRef<Path>>(&self, dirs: &[P]) -> Result<usize> {
+        let mut entries = vec![];
+        for dir in dirs {
+            for p in images_in(dir, self.template.depth())? {
+                match ArchiveEntry::from_file(&p, &self.source, &self.template) {
+                    Ok(e) => entries.push(e),
+                    Err(e) => warn!("Skipping {}: {e}", p.display()),
+                }
+            }
+        }
+        entries.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        self.rewrite(entries.iter())?;
+        Ok(entries.len())
+    }
+
+    fn rewrite<'a>(&self, entries: impl Iterator<Item = &'a ArchiveEntry>) -> Result<()> {
+        let (tmp, fd) = AtomicFile::create(&self.path)?;
+        let mut fd = BufWriter::new(fd);
+        for e in entries {
+            writeln!(fd, "{}", serde_json::to_string(e)?)?;
+        }
+        fd.flush()?;
+        drop(fd);
+        tmp.commit()
+    }
+}
+
+/// `p` as it'd be recorded, which needn't exist anymore so long as its dir does.
+fn key(p: &Path) -> PathBuf {
+    if let Ok(p) = std::fs::canonicalize(p) {
+        return p;
+    }
+    match (p.parent(), p.file_name()) {
+        (Some(dir), Some(name)) => std::fs::canonicalize(dir)
+            .map(|dir| dir.join(name))
+            .unwrap_or_else(|_| p.to_path_buf()),
+        _ => p.to_path_buf(),
+    }
+}
+
+/// sha256 of the file at `p`, as hex.
+pub fn checksum<P: AsRef<Path>>(p: P) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut BufReader::new(File::open(p)?), &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::himawaridt::PRODUCT;
+
+    fn entry(timestamp: &str, path: &str) -> ArchiveEntry {
+        ArchiveEntry {
+            timestamp: timestamp.into(),
+            source: SOURCE.into(),
+            product: PRODUCT.into(),
+            width: 5120,
+            height: 5120,
+            path: path.into(),
+            size: 0,
+            sha256: String::new(),
+        }
+    }
+
+    #[test]
+    fn record_find_and_forget() {
+        let dir = std::env::temp_dir().join("rustwari_record_find_and_forget");
+        std::fs::create_dir_all(&dir).unwrap();
+        let archive = Archive::new(dir.join(MANIFEST));
+        _ = std::fs::remove_file(&archive.path);
+
+        archive.record(&entry("2022-09-21 00:10", "/b")).unwrap();
+        archive.record(&entry("2022-09-20 23:50", "/a")).unwrap();
+        archive.record(&entry("2022-10-01 12:00", "/c")).unwrap();
+        // Re-recording a path replaces it.
+        archive.record(&entry("2022-09-21 00:20", "/b")).unwrap();
+
+        let all = archive.entries().unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[0].path, PathBuf::from("/a"));
+
+        let sept = archive.find("2022-09").unwrap();
+        assert_eq!(sept.len(), 2);
+        assert_eq!(sept[1].timestamp, "2022-09-21 00:20");
+
+        archive.forget(&[PathBuf::from("/a")]).unwrap();
+        assert_eq!(archive.entries().unwrap().len(), 2);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn verify_spots_missing_and_changed_files() {
+        let dir = std::env::temp_dir().join("rustwari_verify_spots_missing_and_changed_files");
+        std::fs::create_dir_all(&dir).unwrap();
+        let archive = Archive::new(dir.join(MANIFEST));
+        _ = std::fs::remove_file(&archive.path);
+
+        let p = dir.join("fulldisc-2022-09-21 00_10.png");
+        std::fs::write(&p, b"original").unwrap();
+        let mut e = entry("2022-09-21 00:10", p.to_str().unwrap());
+        e.sha256 = checksum(&p).unwrap();
+        archive.record(&e).unwrap();
+        archive
+            .record(&entry("2022-09-21 00:20", "/does/not/exist.png"))
+            .unwrap();
+        assert_eq!(archive.verify().unwrap().len(), 1);
+
+        std::fs::write(&p, b"tampered").unwrap();
+        let problems = archive.verify().unwrap();
+        assert_eq!(problems.len(), 2);
+        assert!(matches!(problems[0].1, Problem::ChecksumMismatch { .. }));
+        assert_eq!(problems[1].1, Problem::Missing);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,201 @@
+//! Bulk downloading of historical discs, resumable should it be interrupted.
+
+use crate::cli::Cli;
+use crate::cvutils::{assemble_full_disc_in, COLMAX, ROWMAX};
+use crate::filenames::frame_path;
+use crate::fileutils::AtomicFile;
+use crate::himawaridt::{HimawariDatetime, HimawariRange};
+use crate::sources::TileSource;
+use crate::tiles::{fetch_tiles, is_placeholder, replay, FetchBudget, TileStore};
+use crate::user_config::Config;
+
+use anyhow::Result;
+use futures::StreamExt;
+use log::{debug, error, info, warn};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The state file's name, it lives in the `backup` dir unless told otherwise.
+pub const STATE_FILE: &str = "backfill-state.json";
+
+/// What to backfill, and how.
+#[derive(Debug, Clone)]
+pub struct BackfillOpts {
+    pub range: HimawariRange,
+    /// Keep the raw tiles, rather than assembling discs.
+    pub tiles: bool,
+    /// How many frames are fetched at once.
+    pub jobs: usize,
+    pub budget: FetchBudget,
+    pub source: TileSource,
+    pub state_file: PathBuf,
+}
+
+/// Progress through a backfill, persisted after every frame so we can pick up where we left off.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackfillState {
+    pub from: String,
+    pub to: String,
+    pub every_minutes: i64,
+    pub done: BTreeSet<String>,
+    pub failed: BTreeSet<String>,
+}
+
+impl BackfillState {
+    /// Load the state for `opts`, a state file for a different range is ignored.
+    pub fn load(opts: &BackfillOpts) -> Result<Self> {
+        let fresh = Self {
+            from: opts.range.start.to_string(),
+            to: opts.range.end.to_string(),
+            every_minutes: opts.range.step.num_minutes(),
+            ..Default::default()
+        };
+
+        if !opts.state_file.is_file() {
+            return Ok(fresh);
+        }
+        let existing: Self = serde_json::from_str(&std::fs::read_to_string(&opts.state_file)?)?;
+        match (existing.from == fresh.from && existing.to == fresh.to)
+            && existing.every_minutes == fresh.every_minutes
+        {
+            true => {
+                info!(
+                    "Resuming backfill, {} frames already done.",
+                    existing.done.len()
+                );
+                Ok(existing)
+            }
+            false => Ok(fresh),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, p: P) -> Result<()> {
+        let (tmp, mut fd) = AtomicFile::create(p)?;
+        fd.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        drop(fd);
+        tmp.commit()
+    }
+}
+
+/// Walk the range in `opts`, skipping any frames already present (or done in a previous run).
+pub async fn backfill(
+    client: &Client,
+    uc: &Config,
+    cli: &Cli,
+    opts: &BackfillOpts,
+) -> Result<BackfillState> {
+    let mut state = BackfillState::load(opts)?;
+    let store = TileStore::from_config(uc);
+
+    let todo = opts
+        .range
+        .filter(|t| !state.done.contains(&t.to_string()))
+        .collect::<Vec<_>>();
+    info!("Backfilling {} frames", todo.len());
+
+    let mut results = futures::stream::iter(todo)
+        .map(|t| {
+            let store = &store;
+            async move { (t, backfill_frame(client, uc, cli, opts, store, t).await) }
+        })
+        .buffer_unordered(opts.jobs.max(1));
+
+    while let Some((t, res)) = results.next().await {
+        let ts = t.to_string();
+        match res {
+            Ok(true) => {
+                info!("{ts} done");
+                state.failed.remove(&ts);
+                state.done.insert(ts);
+            }
+            Ok(false) => {
+                warn!("{ts} incomplete, will retry next time");
+                state.failed.insert(ts);
+            }
+            Err(e) => {
+                error!("{ts} failed: {e}");
+                state.failed.insert(ts);
+            }
+        }
+        state.save(&opts.state_file)?;
+    }
+
+    Ok(state)
+}
+
+/// Fetch, and store a single frame, returns false if any tile couldn't be had (or was a placeholder).
+async fn backfill_frame(
+    client: &Client,
+    uc: &Config,
+    cli: &Cli,
+    opts: &BackfillOpts,
+    store: &TileStore,
+    hwdt: HimawariDatetime,
+) -> Result<bool> {
+    if already_present(uc, opts, store, &hwdt) {
+        debug!("Already have: {hwdt:?}");
+        // It may only have been cached, for filling in missing tiles, which are tidied away.
+        if opts.tiles {
+            store.unmark_cached(&hwdt);
+        }
+        return Ok(true);
+    }
+
+    let tiles = fetch_tiles(client, &opts.source, hwdt, opts.budget.clone()).await?;
+    if tiles.len() < (ROWMAX * COLMAX) as usize {
+        return Ok(false);
+    }
+    // NICT's "No Image" tiles may be real ones later, so the frame's retried rather than done.
+    let placeholders = tiles
+        .iter()
+        .filter(|(bytes, _)| is_placeholder(bytes))
+        .count();
+    if placeholders > 0 {
+        debug!("{hwdt} has {placeholders} placeholder tiles");
+        return Ok(false);
+    }
+
+    if opts.tiles {
+        for (bytes, rt) in tiles.iter() {
+            store.save(&hwdt, rt, bytes)?;
+        }
+        store.unmark_cached(&hwdt);
+    } else {
+        let fd = assemble_full_disc_in(&uc.backup, hwdt, uc, cli, replay(tiles), None).await?;
+        debug!("Backfilled: {}", fd.path.display());
+    }
+    Ok(true)
+}
+
+fn already_present(
+    uc: &Config,
+    opts: &BackfillOpts,
+    store: &TileStore,
+    hwdt: &HimawariDatetime,
+) -> bool {
+    match opts.tiles {
+        true => store.has_frame(hwdt),
+        false => frame_path(&uc.backup, uc, *hwdt).is_file(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn frames_snap_to_the_cadence() {
+        let from = "2022-09-21 00:13".parse().unwrap();
+        let to = "2022-09-21 01:10".parse().unwrap();
+        let f = HimawariRange::new(from, to, Duration::minutes(20)).unwrap();
+        assert_eq!(
+            f.map(|t| t.datetime().format("%H:%M").to_string())
+                .collect::<Vec<_>>(),
+            vec!["00:10", "00:30", "00:50", "01:10"]
+        );
+    }
+}
@@ -1,17 +1,23 @@
 //! Main controls for the CLI.
 
-use clap::Parser; //, Subcommand};
+use crate::cvutils::RESIZE_TARGET;
+use crate::preview::Protocol;
+use crate::progress::ProgressStyle;
+use crate::timelapse::Interpolation;
+
+use clap::error::ErrorKind;
+use clap::{ArgAction, CommandFactory, Parser, Subcommand};
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
-    /// Enjoy a silent app with nothing more than a progressbar.
-    #[arg(short, long, default_value_t = true)]
-    pub quiet: bool,
+    /// Log less, -q for warnings only, -qq errors only, -qqq nothing at all.
+    #[arg(short, long, action = ArgAction::Count)]
+    pub quiet: u8,
 
-    /// Not reccomended unless developing.
-    #[arg(short, long, default_value_t = false)]
-    pub verbose: bool,
+    /// Log more, -v for debug, -vv for trace. RUST_LOG overrides both.
+    #[arg(short, long, action = ArgAction::Count)]
+    pub verbose: u8,
 
     /// <WIP>Override the `completed` dir in your existing config file.
     #[arg(long)]
@@ -30,17 +36,220 @@ pub struct Cli {
     #[arg(long, default_value_t = false)]
     pub open: bool,
 
-    /// Get the entry for YYYY-MM-DD HH:MM, note you'll need to wrap it all in ' or "s
-    #[arg(long)]
+    /// Get the entry for YYYY-MM-DD HH:MM, latest, -3h, 'yesterday 12:00 Asia/Tokyo' or a range
+    /// like 'yesterday..latest/1h', note you'll need to wrap it all in ' or "s
+    #[arg(long, allow_hyphen_values = true)]
     pub oneshot: Option<String>,
 
     /// Do you want the app to backup used backgrounds to an alternative directory?
     #[arg(long, default_value_t = false)]
     pub backup: bool,
+
+    /// Print what would be fetched, saved, set and pruned (by a normal run or --oneshot) without
+    /// doing any of it.
+    #[arg(long, default_value_t = false)]
+    pub dry_run: bool,
+
+    /// How to report progress: a bar, nothing, log lines or JSON lines (on stdout).
+    #[arg(long, value_enum, default_value_t = ProgressStyle::Bar)]
+    pub progress: ProgressStyle,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Things to do other than fetching & setting wallpapers, which is what happens when none is given.
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Apply the retention policies from your config.yml to the `completed` and `backup` dirs.
+    Prune {
+        /// Only list what would be removed.
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+
+    /// Query and maintain the index of every image rustwari has saved.
+    Archive {
+        #[command(subcommand)]
+        action: ArchiveCommand,
+    },
+
+    /// Download every disc in a range into your `backup` dir, re-running it picks up where it left off.
+    Backfill {
+        /// YYYY-MM-DD HH:MM, yesterday, -3d etc, or a whole range i.e 'yesterday..latest/1h'
+        #[arg(long, allow_hyphen_values = true)]
+        from: String,
+
+        /// Inclusive, `latest` if `--from` is a range without an end.
+        #[arg(long, allow_hyphen_values = true)]
+        to: Option<String>,
+
+        /// How far apart the frames should be i.e 10m, 3h, 1d, must be a multiple of 10 minutes.
+        /// Defaults to 10m, or the step given in `--from`.
+        #[arg(long)]
+        every: Option<String>,
+
+        /// Keep the raw tiles (in your `tilesdir`) rather than assembling discs.
+        #[arg(long, default_value_t = false)]
+        tiles: bool,
+
+        /// How many frames to fetch at once.
+        #[arg(long, default_value_t = 1)]
+        jobs: usize,
+
+        /// The most tile requests to have in flight at once.
+        #[arg(long, default_value_t = 100)]
+        max_connections: usize,
+
+        /// The most tile requests to start per second, unlimited by default.
+        #[arg(long)]
+        rate: Option<u32>,
+
+        /// How many times to retry a tile before giving up on its frame (for this run).
+        #[arg(long, default_value_t = 5)]
+        retries: u32,
+
+        /// Where progress is kept, defaults to `backfill-state.json` in your `backup` dir.
+        #[arg(long)]
+        state_file: Option<String>,
+    },
+
+    /// Write numbered frames for a time-lapse, from what's in your archive (fetching the rest).
+    Timelapse {
+        /// The frames to use, i.e 'yesterday..latest/30m', see --oneshot.
+        #[arg(allow_hyphen_values = true)]
+        range: String,
+
+        /// Where the frames go.
+        #[arg(long, default_value = "timelapse")]
+        out: String,
+
+        /// How to make up frames in between the real ones.
+        #[arg(long, value_enum, default_value_t = Interpolation::None)]
+        interpolation: Interpolation,
+
+        /// How many frames to make up between each pair of real ones.
+        #[arg(long, default_value_t = 2)]
+        between: u32,
+
+        /// Width (and height) in px of the frames, to shrink them, they're as big as the discs
+        /// otherwise.
+        #[arg(long)]
+        size: Option<u32>,
+    },
+
+    /// Show a disc in the terminal, handy over SSH.
+    Preview {
+        /// A path, or a time like --oneshot takes, the newest disc you have if not given.
+        #[arg(allow_hyphen_values = true)]
+        what: Option<String>,
+
+        /// How to draw it, worked out from $TERM etc unless given.
+        #[arg(long, value_enum, default_value_t = Protocol::Auto)]
+        protocol: Protocol,
+
+        /// How many columns wide, the whole terminal by default.
+        #[arg(long)]
+        width: Option<u16>,
+    },
+
+    /// Fetch the latest disc every ten minutes and share it over HTTP, rather than setting it as
+    /// your wallpaper.
+    Serve {
+        /// Address to listen on.
+        #[arg(long, default_value = "0.0.0.0:8080")]
+        bind: String,
+
+        /// Width (and height) in px of the jpeg at /latest.jpg
+        #[arg(long, default_value_t = RESIZE_TARGET)]
+        size: u32,
+
+        /// Jpeg quality of /latest.jpg, 1-100.
+        #[arg(long, default_value_t = 90)]
+        quality: u8,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ArchiveCommand {
+    /// List everything in the archive, oldest first.
+    List,
+
+    /// Find images by the start of their timestamp i.e '2022-09' or "2022-09-21 00:10"
+    Find { query: String },
+
+    /// Check every archived image is still there, and unchanged.
+    Verify,
+
+    /// Rebuild the archive from the images in your `completed` and `backup` dirs.
+    Reindex,
 }
 
 impl Cli {
     pub fn init() -> Self {
-        Cli::parse()
+        Cli::parse().checked().unwrap_or_else(|e| e.exit())
+    }
+
+    /// --dry-run can only plan a normal run or --oneshot (and prune), anything else that'd write
+    /// or fetch is refused rather than quietly done for real.
+    pub fn checked(self) -> Result<Self, clap::Error> {
+        let doer = match &self.command {
+            Some(Command::Backfill { .. }) => "backfill",
+            Some(Command::Timelapse { .. }) => "timelapse",
+            Some(Command::Serve { .. }) => "serve",
+            Some(Command::Archive {
+                action: ArchiveCommand::Reindex,
+            }) => "archive reindex",
+            _ => return Ok(self),
+        };
+        match self.dry_run {
+            true => Err(Cli::command().error(
+                ErrorKind::ArgumentConflict,
+                format!(
+                    "--dry-run can't be used with {doer}, only a normal run, --oneshot or prune"
+                ),
+            )),
+            false => Ok(self),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_times_arent_flags() {
+        let cli = Cli::try_parse_from(["rustwari", "--oneshot", "-3h"]).unwrap();
+        assert_eq!(cli.oneshot.as_deref(), Some("-3h"));
+
+        for args in [
+            &["rustwari", "backfill", "--from", "-7d", "--to", "-1d"][..],
+            &["rustwari", "timelapse", "-1d../30m"],
+            &["rustwari", "preview", "-3h"],
+        ] {
+            assert!(Cli::try_parse_from(args).is_ok(), "{args:?}");
+        }
+    }
+
+    #[test]
+    fn dry_runs_dont_do_things() {
+        let checked = |args: &[&str]| Cli::try_parse_from(args).unwrap().checked();
+        for args in [
+            &["rustwari", "--dry-run"][..],
+            &["rustwari", "--dry-run", "--oneshot", "-3h"],
+            &["rustwari", "--dry-run", "prune"],
+            &["rustwari", "--dry-run", "archive", "list"],
+        ] {
+            assert!(checked(args).is_ok(), "{args:?}");
+        }
+        for args in [
+            &["rustwari", "--dry-run", "backfill", "--from", "-1d"][..],
+            &["rustwari", "--dry-run", "timelapse", "-1d../30m"],
+            &["rustwari", "--dry-run", "serve"],
+            &["rustwari", "--dry-run", "archive", "reindex"],
+        ] {
+            assert!(checked(args).is_err(), "{args:?}");
+        }
     }
 }
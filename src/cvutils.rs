@@ -1,79 +1,627 @@
+use crate::archive::Archive;
 use crate::cli::Cli;
+use crate::filenames::frame_path;
+use crate::fileutils::AtomicFile;
 use crate::himawaridt::HimawariDatetime;
-use crate::tiles::{img_from, RemoteTile};
+use crate::metrics::metrics;
+use crate::progress::{self, ProgressEvent};
+use crate::tiles::{img_from, is_placeholder, RemoteTile, TileFallback, TooManyMissing};
 use crate::user_config::Config;
 use crate::wallpaperutils::FullDisc;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use bytes::Bytes;
-use image::{GenericImage, ImageBuffer};
-use kdam::term::Colorizer;
-use kdam::{tqdm, BarExt};
-use log::{debug, error};
+use image::codecs::jpeg::{JpegDecoder, JpegEncoder};
+use image::codecs::png::{CompressionType, PngEncoder};
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::{ColorType, DynamicImage, ImageEncoder, ImageFormat, Rgb, RgbImage};
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
 use std::path::Path;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::Receiver;
 
 pub const ROWMAX: u32 = 20;
 pub const COLMAX: u32 = 20;
 pub const TILE_WIDTH: u32 = 550;
 
-/// Helper to build the entire disk, it uses the row/col numbers
-/// from the HashMap in which the LocalTiles are stored to place them into a buffer.
+/// The size (in px) the full disc is shrunk to when resizing is requested.
+pub const RESIZE_TARGET: u32 = 5120;
+
+/// The encoders rustwari can write completed images with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Png,
+    Jpeg,
+    /// Lossless only.
+    Webp,
+    /// Requires building with `--features avif`.
+    Avif,
+}
+
+/// How hard the .png encoder should try.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PngCompression {
+    Fast,
+    #[default]
+    Default,
+    Best,
+}
+
+/// Controls how completed images are encoded, lives under `output:` in the config.yml
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutputSettings {
+    pub format: OutputFormat,
+    /// Only used by png.
+    pub compression: PngCompression,
+    /// 1-100, only used by jpeg and avif.
+    pub quality: u8,
+    /// Drop the alpha channel, there's nothing in it anyway and it's a quarter of the file.
+    pub rgb: bool,
+}
+
+impl Default for OutputSettings {
+    fn default() -> Self {
+        Self {
+            format: OutputFormat::Png,
+            compression: PngCompression::Default,
+            quality: 90,
+            rgb: false,
+        }
+    }
+}
+
+impl OutputSettings {
+    /// The file extension matching the configured format.
+    pub fn extension(&self) -> &'static str {
+        match self.format {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Avif => "avif",
+        }
+    }
+
+    /// The [`ColorType`] pixels should be handed to [`write_raw`] in.
+    pub fn color_type(&self) -> ColorType {
+        // jpeg has no alpha channel to speak of.
+        match self.rgb || self.format == OutputFormat::Jpeg {
+            true => ColorType::Rgb8,
+            false => ColorType::Rgba8,
+        }
+    }
+
+    /// Converts `img` into the [`ColorType`] we're outputting.
+    pub(crate) fn pixels_of(&self, img: &DynamicImage) -> Vec<u8> {
+        match self.color_type() {
+            ColorType::Rgb8 => img.to_rgb8().into_raw(),
+            _ => img.to_rgba8().into_raw(),
+        }
+    }
+}
+
+/// Encode raw pixels, (as laid out by [`OutputSettings::color_type`]), with the configured encoder.
+pub fn write_raw<W: Write>(
+    w: W,
+    buf: &[u8],
+    width: u32,
+    height: u32,
+    out: &OutputSettings,
+) -> Result<()> {
+    let color = out.color_type();
+    match out.format {
+        OutputFormat::Png => {
+            let compression = match out.compression {
+                PngCompression::Fast => CompressionType::Fast,
+                PngCompression::Default => CompressionType::Default,
+                PngCompression::Best => CompressionType::Best,
+            };
+            PngEncoder::new_with_quality(w, compression, image::codecs::png::FilterType::Adaptive)
+                .write_image(buf, width, height, color)?
+        }
+        OutputFormat::Jpeg => {
+            JpegEncoder::new_with_quality(w, out.quality).write_image(buf, width, height, color)?
+        }
+        OutputFormat::Webp => {
+            WebPEncoder::new_lossless(w).write_image(buf, width, height, color)?
+        }
+        #[cfg(feature = "avif")]
+        OutputFormat::Avif => {
+            image::codecs::avif::AvifEncoder::new_with_speed_quality(w, 6, out.quality)
+                .write_image(buf, width, height, color)?
+        }
+        #[cfg(not(feature = "avif"))]
+        OutputFormat::Avif => {
+            anyhow::bail!("avif output requires rustwari to be built with `--features avif`")
+        }
+    }
+    Ok(())
+}
+
+/// Helper to build the entire disk, tiles are buffered (still compressed) until their whole row
+/// has arrived, at which point that row is decoded and streamed straight into the encoder.
+/// This keeps peak memory to roughly a single row of decoded tiles rather than the whole 121MP image.
+/// Any tiles that never arrive are taken from `fallback`, when there is one.
 pub async fn assemble_full_disc(
     hwdt: HimawariDatetime,
     uc: &Config,
     cli: &Cli,
+    rx: Receiver<(Bytes, RemoteTile)>,
+    fallback: Option<&TileFallback>,
+) -> Result<FullDisc> {
+    assemble_full_disc_in(&uc.completed, hwdt, uc, cli, rx, fallback).await
+}
+
+/// How big each tile is in the assembled disc.
+pub fn tile_px_for(cli: &Cli) -> u32 {
+    // Downsampling each tile as it lands means we never have to decode the full sized image again.
+    match cli.resize {
+        true => RESIZE_TARGET / COLMAX,
+        false => TILE_WIDTH,
+    }
+}
+
+/// [`assemble_full_disc`], saving to `dir` rather than the `completed` dir.
+pub async fn assemble_full_disc_in<P: AsRef<Path>>(
+    dir: P,
+    hwdt: HimawariDatetime,
+    uc: &Config,
+    cli: &Cli,
+    rx: Receiver<(Bytes, RemoteTile)>,
+    fallback: Option<&TileFallback>,
+) -> Result<FullDisc> {
+    let p = frame_path(dir, uc, hwdt);
+    let fd = assemble_to_with(&p, tile_px_for(cli), uc.output, rx, fallback).await?;
+
+    Archive::from_config(uc).record_file(&p);
+
+    if cli.verbose > 0 {
+        debug!(
+            "IMAGE:{} {}x{} {}bytes",
+            p.display(),
+            fd.width,
+            fd.height,
+            fd.size
+        );
+    }
+
+    Ok(fd)
+}
+
+/// Assemble the tiles from `rx` into an image at `p`, each tile being `tile_px` square.
+/// Missing tiles are left black.
+pub async fn assemble_to<P: AsRef<Path>>(
+    p: P,
+    tile_px: u32,
+    out: OutputSettings,
+    rx: Receiver<(Bytes, RemoteTile)>,
+) -> Result<FullDisc> {
+    assemble_to_with(p, tile_px, out, rx, None).await
+}
+
+/// [`assemble_to`], filling in missing tiles from `fallback`. Should more be missing than it
+/// allows, nothing is saved.
+pub async fn assemble_to_with<P: AsRef<Path>>(
+    p: P,
+    tile_px: u32,
+    out: OutputSettings,
     mut rx: Receiver<(Bytes, RemoteTile)>,
+    fallback: Option<&TileFallback>,
 ) -> Result<FullDisc> {
     debug!("Assembling full disc...");
+    let p = p.as_ref();
 
-    // I like to see progress bars.
-    let mut pb = tqdm!(
-        0..400,
-        bar_format = format!(
-            "Progress: {{animation}} {}",
-            "{percentage:3.0}%".colorize("#EE6FF8")
-        ),
-        colour = "gradient(#5A56E0,#EE6FF8)",
-        force_refresh = true
-    );
-
-    // The final image that we're making
-    let mut fulldisc = ImageBuffer::new(ROWMAX * TILE_WIDTH, COLMAX * TILE_WIDTH);
+    let (tmp, fd) = AtomicFile::create(p)?;
+    let mut rows = RowStreamer::new(fd, tile_px, out)?;
+    let mut started = None;
+    let mut encoding = Duration::ZERO;
 
     //NOTE: .par_iter() was tested extensively and showed no appreciable benefits (even on my 32 core system.)
     while let Some((bytes, rt)) = rx.recv().await {
-        let x_offset = rt.x * TILE_WIDTH;
-        let y_offset = rt.y * TILE_WIDTH;
+        let t1 = Instant::now();
+        started.get_or_insert(t1);
+        let (x, y) = (rt.x, rt.y);
+        if let Some(fallback) = fallback {
+            // Left missing, so something real stands in for it below.
+            if is_placeholder(&bytes) {
+                debug!("x{x}, y{y} is only NICT's placeholder");
+                continue;
+            }
+            fallback.keep(&rt, &bytes);
+        }
+        rows.push(rt, bytes)?;
+        encoding += t1.elapsed();
+        progress::emit(ProgressEvent::TileAssembled { x, y });
+    }
+
+    if let Some(fallback) = fallback {
+        let missing = rows.missing();
+        if missing.len() > fallback.max_substituted {
+            return Err(TooManyMissing {
+                hwdt: fallback.hwdt,
+                missing: missing.len(),
+                allowed: fallback.max_substituted,
+            }
+            .into());
+        }
+        for (x, y) in missing {
+            match fallback.find(x, y) {
+                Some((bytes, rt, from)) => {
+                    rows.push(rt, bytes)?;
+                    metrics().tiles_substituted.inc();
+                    progress::emit(ProgressEvent::TileSubstituted {
+                        x,
+                        y,
+                        from: from.to_string(),
+                    });
+                    progress::emit(ProgressEvent::TileAssembled { x, y });
+                }
+                None => warn!("x{x}, y{y} is missing, with nothing to stand in for it"),
+            }
+        }
+    }
+
+    let t1 = Instant::now();
+    // Should either fail, the temp file's removed as `tmp` is dropped.
+    rows.finish()
+        .and_then(|_| tmp.commit())
+        .with_context(|| format!("Error saving: {}", p.display()))?;
+    if let Some(fallback) = fallback {
+        fallback.tidy();
+    }
+    encoding += t1.elapsed();
+    metrics().encode_duration.observe(encoding.as_secs_f64());
+    metrics()
+        .assembly_duration
+        .observe(started.unwrap_or(t1).elapsed().as_secs_f64());
+    progress::emit(ProgressEvent::Saved {
+        path: p.to_path_buf(),
+    });
+
+    FullDisc::new(p, out)
+}
 
-        let img = img_from(bytes);
+/// Where finished rows of the disc end up.
+enum RowSink<W: Write + 'static> {
+    /// Rows go straight into the .png encoder as they're completed.
+    Png(Box<png::StreamWriter<'static, W>>),
+    /// The other encoders want the whole image in one go, so rows are collected until [`RowStreamer::finish`].
+    Buffered(W, Vec<u8>),
+}
+
+/// Writes the disc out one row of tiles at a time, tiles can arrive in any order, they're
+/// held (compressed) until every row above them has been written.
+pub(crate) struct RowStreamer<W: Write + 'static> {
+    sink: RowSink<W>,
+    out: OutputSettings,
+    tile_px: u32,
+    next_row: u32,
+    pending: BTreeMap<u32, Vec<Option<(Bytes, RemoteTile)>>>,
+}
 
-        match fulldisc.copy_from(&img, x_offset, y_offset) {
-            Ok(_) => {
-                pb.update(1);
+impl<W: Write + 'static> RowStreamer<W> {
+    pub(crate) fn new(w: W, tile_px: u32, out: OutputSettings) -> Result<Self> {
+        let sink = match out.format {
+            OutputFormat::Png => {
+                let mut encoder = png::Encoder::new(w, COLMAX * tile_px, ROWMAX * tile_px);
+                encoder.set_color(match out.color_type() {
+                    ColorType::Rgb8 => png::ColorType::Rgb,
+                    _ => png::ColorType::Rgba,
+                });
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder.set_compression(match out.compression {
+                    PngCompression::Fast => png::Compression::Fast,
+                    PngCompression::Default => png::Compression::Default,
+                    PngCompression::Best => png::Compression::Best,
+                });
+                RowSink::Png(Box::new(encoder.write_header()?.into_stream_writer()?))
+            }
+            _ => {
+                if tile_px == TILE_WIDTH {
+                    warn!(
+                        "{:?} output can't be streamed, the whole 121MP disc will be held in memory.",
+                        out.format
+                    );
+                }
+                RowSink::Buffered(w, vec![])
             }
-            Err(e) => error!("{e} on x{}, y{}", rt.x, rt.y),
         };
+
+        Ok(Self {
+            sink,
+            out,
+            tile_px,
+            next_row: 0,
+            pending: BTreeMap::new(),
+        })
     }
 
-    pb.completed();
-    eprintln!(); // Creating space for the progressbars.
+    /// Accept a tile, flushing as many complete rows as are now available.
+    pub(crate) fn push(&mut self, rt: RemoteTile, bytes: Bytes) -> Result<()> {
+        if rt.y < self.next_row || rt.x >= COLMAX || rt.y >= ROWMAX {
+            error!("Unexpected tile x{}, y{}, skipping it.", rt.x, rt.y);
+            return Ok(());
+        }
+
+        let row = self
+            .pending
+            .entry(rt.y)
+            .or_insert_with(|| vec![None; COLMAX as usize]);
+        let x = rt.x as usize;
+        row[x] = Some((bytes, rt));
+
+        while self
+            .pending
+            .get(&self.next_row)
+            .is_some_and(|r| r.iter().all(Option::is_some))
+        {
+            self.flush_next_row()?;
+        }
+        Ok(())
+    }
+
+    /// Where every tile that's yet to arrive goes.
+    pub(crate) fn missing(&self) -> Vec<(u32, u32)> {
+        (self.next_row..ROWMAX)
+            .flat_map(|y| (0..COLMAX).map(move |x| (x, y)))
+            .filter(|(x, y)| {
+                self.pending
+                    .get(y)
+                    .is_none_or(|row| row[*x as usize].is_none())
+            })
+            .collect()
+    }
+
+    /// Write whatever remains, any tiles that never showed up are left black.
+    pub(crate) fn finish(mut self) -> Result<()> {
+        while self.next_row < ROWMAX {
+            self.flush_next_row()?;
+        }
+        match self.sink {
+            RowSink::Png(writer) => writer.finish()?,
+            RowSink::Buffered(w, buf) => {
+                let px = COLMAX * self.tile_px;
+                let mut w = BufWriter::new(w);
+                write_raw(&mut w, &buf, px, ROWMAX * self.tile_px, &self.out)?;
+                w.flush()?;
+            }
+        }
+        Ok(())
+    }
 
-    let p = std::path::Path::new(&uc.completed).join(hwdt.pretty_filename());
+    fn flush_next_row(&mut self) -> Result<()> {
+        let tiles = self.pending.remove(&self.next_row).unwrap_or_default();
+        let tile_px = self.tile_px as usize;
+        let channels = self.out.color_type().channel_count() as usize;
+        let stride = tile_px * COLMAX as usize * channels;
+        let mut buf = vec![0u8; stride * tile_px];
 
-    fulldisc
-        .save(&p)
-        .unwrap_or_else(|_| panic!("Error saving: {}", p.display()));
+        for (bytes, rt) in tiles.into_iter().flatten() {
+            let img = match img_from(bytes) {
+                Ok(img) => img,
+                Err(e) => {
+                    error!("{e} on x{}, y{}", rt.x, rt.y);
+                    continue;
+                }
+            };
+            let tile = self.out.pixels_of(&downsample(img, self.tile_px));
+
+            let x_offset = rt.x as usize * tile_px * channels;
+            for (row_idx, src) in tile.chunks_exact(tile_px * channels).enumerate() {
+                let start = row_idx * stride + x_offset;
+                buf[start..start + tile_px * channels].copy_from_slice(src);
+            }
+        }
+
+        match &mut self.sink {
+            RowSink::Png(writer) => writer.write_all(&buf)?,
+            RowSink::Buffered(_, all) => all.extend_from_slice(&buf),
+        }
+        self.next_row += 1;
+        Ok(())
+    }
+}
+
+/// Shrinks a tile to `tile_px` square, tiles already at that size are passed straight through.
+fn downsample(img: DynamicImage, tile_px: u32) -> DynamicImage {
+    if img.width() == tile_px && img.height() == tile_px {
+        return img;
+    }
+    img.resize_exact(tile_px, tile_px, FilterType::Lanczos3)
+}
+
+/// Get the dimensions of an image from the file path, only the header is read.
+pub fn get_dims<P: AsRef<Path>>(p: P) -> Result<(u32, u32)> {
+    Ok(image::image_dimensions(p)?)
+}
+
+/// The image at `p` shrunk to fit in `px` square, without decoding all 121MP of a full sized disc
+/// at once. Png is read a row at a time (into a box filter), jpeg is decoded at a reduced scale,
+/// anything else has to be opened in full.
+pub fn thumbnail<P: AsRef<Path>>(p: P, px: u32) -> Result<RgbImage> {
+    let p = p.as_ref();
+    let img = match ImageFormat::from_path(p)? {
+        ImageFormat::Png => return png_thumbnail(p, px),
+        ImageFormat::Jpeg => {
+            let mut decoder = JpegDecoder::new(BufReader::new(File::open(p)?))?;
+            let side = px.min(u16::MAX as u32) as u16;
+            decoder.scale(side, side)?;
+            DynamicImage::from_decoder(decoder)?
+        }
+        _ => image::open(p)?,
+    };
+    Ok(match img.width() <= px && img.height() <= px {
+        true => img.to_rgb8(),
+        false => img.thumbnail(px, px).to_rgb8(),
+    })
+}
 
-    if cli.verbose {
-        debug!("IMAGE:{}", p.display());
+fn png_thumbnail(p: &Path, px: u32) -> Result<RgbImage> {
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(p)?));
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let (w, h) = (reader.info().width, reader.info().height);
+    if reader.info().interlaced {
+        // Rows come in 7 passes, not worth the bother for something we never write.
+        return Ok(image::open(p)?.thumbnail(px, px).to_rgb8());
     }
+    let channels = reader.output_color_type().0.samples();
 
-    FullDisc::new(&p)
+    // Each px of the thumbnail is the average of a `step` px square.
+    let step = w.max(h).div_ceil(px.max(1)).max(1);
+    let mut out = RgbImage::new(w.div_ceil(step), h.div_ceil(step));
+    let mut sums = vec![[0u32; 4]; out.width() as usize];
+    let mut y = 0;
+    while let Some(row) = reader.next_row()? {
+        for (x, c) in row.data().chunks_exact(channels).enumerate() {
+            let rgb = match channels {
+                1 | 2 => [c[0]; 3],
+                _ => [c[0], c[1], c[2]],
+            };
+            let sum = &mut sums[x / step as usize];
+            (0..3).for_each(|i| sum[i] += rgb[i] as u32);
+            sum[3] += 1;
+        }
+        y += 1;
+        if y % step == 0 || y == h {
+            for (x, sum) in sums.iter_mut().enumerate() {
+                let n = sum[3].max(1);
+                out.put_pixel(
+                    x as u32,
+                    (y - 1) / step,
+                    Rgb([0, 1, 2].map(|i| (sum[i] / n) as u8)),
+                );
+                *sum = [0; 4];
+            }
+        }
+    }
+    Ok(out)
 }
 
-/// Get the dimensions of an image from the file path.
-pub(crate) fn get_dims<P: AsRef<Path>>(p: P) -> Result<(u32, u32)> {
-    let img = image::open(p)?;
-    Ok((img.height(), img.width()))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageOutputFormat, Rgba, RgbaImage};
+    use std::fs::File;
+    use std::io::Cursor;
+    use url::Url;
+
+    fn tile(x: u32, y: u32) -> (RemoteTile, Bytes) {
+        let img = RgbaImage::from_pixel(8, 8, Rgba([x as u8, y as u8, 0, 255]));
+        let mut out = Cursor::new(vec![]);
+        DynamicImage::ImageRgba8(img)
+            .write_to(&mut out, ImageOutputFormat::Png)
+            .unwrap();
+
+        let url = Url::parse("http://localhost/").unwrap();
+        (RemoteTile { x, y, url }, Bytes::from(out.into_inner()))
+    }
+
+    #[test]
+    fn rows_stream_out_of_order() {
+        let p = std::env::temp_dir().join("rustwari_rows_stream_out_of_order.png");
+        let mut rows =
+            RowStreamer::new(File::create(&p).unwrap(), 4, OutputSettings::default()).unwrap();
+
+        for y in (0..ROWMAX).rev() {
+            for x in 0..COLMAX {
+                let (rt, bytes) = tile(x, y);
+                rows.push(rt, bytes).unwrap();
+            }
+            // Nothing can be written until row 0 turns up, which is last.
+            if y > 0 {
+                assert_eq!(rows.next_row, 0);
+            }
+        }
+        assert_eq!(rows.next_row, ROWMAX);
+        rows.finish().unwrap();
+
+        let img = image::open(&p).unwrap().to_rgba8();
+        assert_eq!(img.dimensions(), (COLMAX * 4, ROWMAX * 4));
+        assert_eq!(img.get_pixel(5 * 4 + 1, 7 * 4 + 2), &Rgba([5, 7, 0, 255]));
+        _ = std::fs::remove_file(p);
+    }
+
+    #[test]
+    fn buffered_formats_drop_alpha() {
+        let p = std::env::temp_dir().join("rustwari_buffered_formats_drop_alpha.jpg");
+        let out = OutputSettings {
+            format: OutputFormat::Jpeg,
+            ..Default::default()
+        };
+        let mut rows = RowStreamer::new(File::create(&p).unwrap(), 4, out).unwrap();
+        let (rt, bytes) = tile(0, 0);
+        rows.push(rt, bytes).unwrap();
+        rows.finish().unwrap();
+
+        let img = image::open(&p).unwrap();
+        assert_eq!(img.color(), ColorType::Rgb8);
+        assert_eq!((img.width(), img.height()), (COLMAX * 4, ROWMAX * 4));
+        _ = std::fs::remove_file(p);
+    }
+
+    #[test]
+    fn thumbnails_are_shrunk_as_theyre_read() {
+        // Red on the left, blue on the right.
+        let img = RgbImage::from_fn(40, 20, |x, _| match x < 20 {
+            true => Rgb([255, 0, 0]),
+            false => Rgb([0, 0, 255]),
+        });
+        for ext in ["png", "jpg"] {
+            let p = std::env::temp_dir().join(format!("rustwari_thumbnails.{ext}"));
+            img.save(&p).unwrap();
+
+            let thumb = thumbnail(&p, 10).unwrap();
+            assert_eq!(thumb.dimensions(), (10, 5), "{ext}");
+            assert!(thumb.get_pixel(0, 0)[0] > 200, "{ext}");
+            assert!(thumb.get_pixel(9, 4)[2] > 200, "{ext}");
+            // Nothing's made bigger.
+            assert_eq!(thumbnail(&p, 100).unwrap().dimensions(), (40, 20));
+            _ = std::fs::remove_file(p);
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_to_save_is_an_error() {
+        // Nothing can be renamed over a directory with something in it.
+        let dir = std::env::temp_dir().join("rustwari_failing_to_save_is_an_error");
+        let p = dir.join("fulldisc.png");
+        std::fs::create_dir_all(p.join("occupied")).unwrap();
+
+        let (rt, bytes) = tile(0, 0);
+        let res = assemble_to(
+            &p,
+            4,
+            OutputSettings::default(),
+            crate::tiles::replay(vec![(bytes, rt)]),
+        )
+        .await;
+        assert!(res.unwrap_err().to_string().starts_with("Error saving"));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn missing_tiles_are_left_black() {
+        let p = std::env::temp_dir().join("rustwari_missing_tiles_are_left_black.png");
+        let mut rows =
+            RowStreamer::new(File::create(&p).unwrap(), 4, OutputSettings::default()).unwrap();
+
+        let (rt, bytes) = tile(3, 2);
+        rows.push(rt, bytes).unwrap();
+        rows.finish().unwrap();
+
+        let img = image::open(&p).unwrap().to_rgba8();
+        assert_eq!(img.get_pixel(3 * 4 + 1, 2 * 4 + 1), &Rgba([3, 2, 0, 255]));
+        assert_eq!(img.get_pixel(0, 0), &Rgba([0, 0, 0, 0]));
+        _ = std::fs::remove_file(p);
+    }
 }
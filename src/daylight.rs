@@ -0,0 +1,187 @@
+//! Where the sun is, so that when it's night where you are the wallpaper can be a disc from when it
+//! wasn't, see the `daylight:` section of the config.yml
+
+use crate::himawaridt::{dataset_start, HimawariDatetime, CADENCE_MINUTES};
+
+use chrono::{DateTime, Duration, Utc};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+/// Himawari sits over the equator at this longitude.
+pub const SUB_SATELLITE_LON: f64 = 140.7;
+
+/// How far back (in frames) we'll look for something better than the latest disc, a day's worth.
+const LOOKBACK_FRAMES: i64 = 24 * 60 / CADENCE_MINUTES as i64;
+
+/// Which disc to use when it's dark at your location.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Prefer {
+    /// The latest, daylight or not.
+    #[default]
+    Latest,
+    /// The most recent disc from when the sun was still up at your location.
+    LastDaylit,
+    /// Whichever disc of the last day had the most of itself lit.
+    Brightest,
+}
+
+/// Lives under `daylight:` in the config.yml, without a location it's always the latest disc.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DaylightSettings {
+    /// Degrees, north is positive.
+    pub latitude: Option<f64>,
+    /// Degrees, east is positive.
+    pub longitude: Option<f64>,
+    pub prefer: Prefer,
+}
+
+/// The latitude and longitude (in degrees) the sun is directly above at `t`.
+pub fn subsolar_point(t: DateTime<Utc>) -> (f64, f64) {
+    // Days since J2000, then the low precision formulae from the Astronomical Almanac, good to
+    // within a fraction of a degree which is plenty for choosing a wallpaper.
+    let n = t.timestamp() as f64 / 86400.0 + 2440587.5 - 2451545.0;
+    let mean_lon = (280.460 + 0.9856474 * n).rem_euclid(360.0);
+    let anomaly = (357.528 + 0.9856003 * n).rem_euclid(360.0).to_radians();
+    let ecliptic_lon =
+        (mean_lon + 1.915 * anomaly.sin() + 0.020 * (2.0 * anomaly).sin()).to_radians();
+    let obliquity = (23.439 - 0.0000004 * n).to_radians();
+
+    let declination = (obliquity.sin() * ecliptic_lon.sin()).asin();
+    let right_ascension = (obliquity.cos() * ecliptic_lon.sin()).atan2(ecliptic_lon.cos());
+    let sidereal = (280.46061837 + 360.98564736629 * n).rem_euclid(360.0);
+
+    let lon = (right_ascension.to_degrees() - sidereal + 540.0).rem_euclid(360.0) - 180.0;
+    (declination.to_degrees(), lon)
+}
+
+/// The angle (in degrees) between points on the globe.
+fn separation((lat1, lon1): (f64, f64), (lat2, lon2): (f64, f64)) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let cos = lat1.sin() * lat2.sin() + lat1.cos() * lat2.cos() * (lon1 - lon2).to_radians().cos();
+    cos.clamp(-1.0, 1.0).acos().to_degrees()
+}
+
+/// How high (in degrees) the sun is above the horizon at `lat`, `lon`, negative is night.
+pub fn sun_elevation(t: DateTime<Utc>, lat: f64, lon: f64) -> f64 {
+    90.0 - separation((lat, lon), subsolar_point(t))
+}
+
+/// The fraction of the disc Himawari sees that's lit, 1.0 is the sun right behind the satellite.
+pub fn disc_illumination(t: DateTime<Utc>) -> f64 {
+    (1.0 + separation((0.0, SUB_SATELLITE_LON), subsolar_point(t))
+        .to_radians()
+        .cos())
+        / 2.0
+}
+
+/// The disc to use at `now`, [`HimawariDatetime::closest_to`] unless it's dark at the configured
+/// location and something else is preferred.
+pub fn choose(settings: &DaylightSettings, now: DateTime<Utc>) -> HimawariDatetime {
+    let latest = HimawariDatetime::closest_to(now);
+    let (Some(lat), Some(lon)) = (settings.latitude, settings.longitude) else {
+        return latest;
+    };
+    let daylit = |t: &HimawariDatetime| sun_elevation(t.datetime(), lat, lon) > 0.0;
+    if settings.prefer == Prefer::Latest || daylit(&latest) {
+        return latest;
+    }
+
+    let earlier = (1..=LOOKBACK_FRAMES)
+        .map(|n| latest - Duration::minutes(CADENCE_MINUTES as i64 * n))
+        .take_while(|t| t.datetime() >= dataset_start());
+    let chosen = match settings.prefer {
+        Prefer::Latest => None,
+        Prefer::LastDaylit => earlier.into_iter().find(daylit),
+        Prefer::Brightest => earlier.into_iter().max_by(|a, b| {
+            disc_illumination(a.datetime()).total_cmp(&disc_illumination(b.datetime()))
+        }),
+    };
+    match chosen {
+        Some(t) => {
+            debug!("It's dark at {lat}, {lon}, using {t} rather than {latest}");
+            t
+        }
+        None => latest,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// Auckland.
+    const AKL: DaylightSettings = DaylightSettings {
+        latitude: Some(-36.85),
+        longitude: Some(174.76),
+        prefer: Prefer::Latest,
+    };
+
+    fn utc(d: u32, h: u32, m: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2022, 6, d, h, m, 0).unwrap()
+    }
+
+    #[test]
+    fn the_sun() {
+        let (lat, lon) = subsolar_point(utc(21, 12, 0));
+        assert!((lat - 23.44).abs() < 0.1, "{lat}");
+        assert!(lon.abs() < 1.0, "{lon}");
+
+        // Midday, and midnight, of the shortest day in Auckland.
+        let noon = sun_elevation(utc(21, 0, 0), -36.85, 174.76);
+        assert!((noon - 29.7).abs() < 1.0, "{noon}");
+        assert!(sun_elevation(utc(21, 12, 0), -36.85, 174.76) < -20.0);
+
+        // Local noon at the satellite is about 02:40 UTC.
+        assert!(disc_illumination(utc(21, 2, 40)) > 0.95);
+        assert!(disc_illumination(utc(21, 14, 40)) < 0.05);
+    }
+
+    #[test]
+    fn choosing() {
+        // The latest disc is from 12:00 UTC, midnight in Auckland.
+        let now = utc(21, 16, 20);
+        assert_eq!(choose(&AKL, now).to_string(), "2022-06-21 12:00");
+        assert_eq!(
+            choose(&DaylightSettings::default(), now),
+            HimawariDatetime::closest_to(now)
+        );
+
+        let last = choose(
+            &DaylightSettings {
+                prefer: Prefer::LastDaylit,
+                ..AKL
+            },
+            now,
+        );
+        let (lat, lon) = (-36.85, 174.76);
+        assert!(sun_elevation(last.datetime(), lat, lon) > 0.0);
+        assert!(sun_elevation((last + Duration::minutes(10)).datetime(), lat, lon) <= 0.0);
+        assert_eq!(last.hour(), 5);
+
+        let brightest = choose(
+            &DaylightSettings {
+                prefer: Prefer::Brightest,
+                ..AKL
+            },
+            now,
+        );
+        assert_eq!(brightest.hour(), 2);
+        assert!([30, 40].contains(&brightest.minute()), "{brightest}");
+
+        // It's daytime, so the latest will do.
+        let now = utc(21, 4, 20);
+        assert_eq!(
+            choose(
+                &DaylightSettings {
+                    prefer: Prefer::Brightest,
+                    ..AKL
+                },
+                now
+            ),
+            HimawariDatetime::closest_to(now)
+        );
+    }
+}
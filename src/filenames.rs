@@ -0,0 +1,368 @@
+//! What finished discs are called, and where beneath `completed`/`backup` they go, as set by
+//! `filename:` in the config.yml. The same template is used to get the timestamp (and whatever
+//! else it mentions) back out of a saved image's path.
+
+use crate::himawaridt::{HimawariDatetime, LEVEL, PRODUCT, SOURCE};
+use crate::sources::TileSource;
+use crate::user_config::Config;
+
+use anyhow::{anyhow, bail, Error, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+/// What we've always called them, i.e `fulldisc-2022-09-21 00_10.png`.
+pub const FILENAME_TEMPLATE: &str = "fulldisc-{year}-{month}-{day} {hour}_{minute}.{ext}";
+
+/// Everything a [`FilenameTemplate`] can say about a disc.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameName {
+    pub hwdt: HimawariDatetime,
+    pub source: String,
+    pub product: String,
+    /// The resolution level, i.e `20d` for a 20x20 grid of tiles.
+    pub level: String,
+    pub ext: String,
+}
+
+impl FrameName {
+    pub fn new(hwdt: HimawariDatetime, source: &TileSource, ext: &str) -> Self {
+        Self {
+            hwdt,
+            source: source.name.clone(),
+            product: source.product.clone(),
+            level: LEVEL.into(),
+            ext: ext.into(),
+        }
+    }
+}
+
+/// Where the disc for `hwdt` goes beneath `dir`, according to the user's config.yml
+pub fn frame_path<P: AsRef<Path>>(dir: P, uc: &Config, hwdt: HimawariDatetime) -> PathBuf {
+    let source = TileSource::from_config(uc).unwrap_or_default();
+    dir.as_ref().join(
+        uc.filename
+            .render(&FrameName::new(hwdt, &source, uc.output.extension())),
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Year,
+    Month,
+    Day,
+    Hour,
+    Minute,
+    Hhmm,
+    Source,
+    Product,
+    Level,
+    Ext,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "year" => Field::Year,
+            "month" => Field::Month,
+            "day" => Field::Day,
+            "hour" => Field::Hour,
+            "minute" => Field::Minute,
+            "hhmm" => Field::Hhmm,
+            "source" => Field::Source,
+            "product" => Field::Product,
+            "level" => Field::Level,
+            "ext" => Field::Ext,
+            _ => return None,
+        })
+    }
+
+    /// How many digits the numeric fields always have, text fields are `None`.
+    fn width(&self) -> Option<usize> {
+        match self {
+            Field::Year | Field::Hhmm => Some(4),
+            Field::Month | Field::Day | Field::Hour | Field::Minute => Some(2),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+/// A template like `{source}/{year}/{month}/{day}/{hhmm}_{level}.{ext}`, relative to the
+/// `completed` or `backup` dir.
+///
+/// One of {year} {month} {day} {hour} {minute} {hhmm} {source} {product} {level} {ext}, the date
+/// and time must be in there somewhere so we can find them again. Text fields ({source} etc)
+/// need something other than another field after them, so we know where they end.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct FilenameTemplate {
+    raw: String,
+    parts: Vec<Part>,
+}
+
+impl Default for FilenameTemplate {
+    fn default() -> Self {
+        FILENAME_TEMPLATE
+            .parse()
+            .expect("the default template is valid")
+    }
+}
+
+impl FilenameTemplate {
+    /// How many directories deep beneath `completed`/`backup` the images are.
+    pub fn depth(&self) -> usize {
+        self.raw.matches('/').count()
+    }
+
+    /// The path, relative to `completed`/`backup`, of the disc described by `name`.
+    pub fn render(&self, name: &FrameName) -> PathBuf {
+        let hwdt = &name.hwdt;
+        let mut out = String::new();
+        for part in self.parts.iter() {
+            match part {
+                Part::Literal(s) => out.push_str(s),
+                Part::Field(f) => out.push_str(&match f {
+                    Field::Year => hwdt.year().to_string(),
+                    Field::Month => format!("{:02}", hwdt.month()),
+                    Field::Day => format!("{:02}", hwdt.day()),
+                    Field::Hour => format!("{:02}", hwdt.hour()),
+                    Field::Minute => format!("{:02}", hwdt.minute()),
+                    Field::Hhmm => format!("{:02}{:02}", hwdt.hour(), hwdt.minute()),
+                    Field::Source => name.source.clone(),
+                    Field::Product => name.product.clone(),
+                    Field::Level => name.level.clone(),
+                    Field::Ext => name.ext.clone(),
+                }),
+            }
+        }
+        PathBuf::from(out)
+    }
+
+    /// Get a [`FrameName`] back from an image saved with this template. Only the last
+    /// [`FilenameTemplate::depth`] directories of `path` are looked at, so wherever it lives is
+    /// irrelevant. Anything the template doesn't mention is the default (NICT) value, bar the `ext`
+    /// which comes from the path.
+    pub fn parse(&self, path: &Path) -> Result<FrameName> {
+        let names = path
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(s) => s.to_str(),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if names.len() < self.depth() + 1 {
+            bail!("{} isn't nested deeply enough for {self}", path.display());
+        }
+        let rel = names[names.len() - self.depth() - 1..].join("/");
+
+        let mut rest = rel.as_str();
+        let mut fields: Vec<(Field, &str)> = vec![];
+        for (i, part) in self.parts.iter().enumerate() {
+            let mismatch = || anyhow!("{} doesn't match {self}", path.display());
+            match part {
+                Part::Literal(lit) => {
+                    rest = rest.strip_prefix(lit.as_str()).ok_or_else(mismatch)?
+                }
+                Part::Field(f) => {
+                    let len = match (f.width(), self.parts.get(i + 1)) {
+                        (Some(w), _) => {
+                            if rest.len() < w || !rest[..w].bytes().all(|b| b.is_ascii_digit()) {
+                                return Err(mismatch());
+                            }
+                            w
+                        }
+                        (None, Some(Part::Literal(next))) => {
+                            rest.find(next.as_str()).ok_or_else(mismatch)?
+                        }
+                        (None, _) => rest.len(),
+                    };
+                    let value = &rest[..len];
+                    if value.is_empty() || value.contains('/') {
+                        return Err(mismatch());
+                    }
+                    fields.push((*f, value));
+                    rest = &rest[len..];
+                }
+            }
+        }
+        if !rest.is_empty() {
+            bail!("{} doesn't match {self}", path.display());
+        }
+
+        let get = |field: Field| fields.iter().find(|(f, _)| *f == field).map(|(_, v)| *v);
+        let num = |field: Field| get(field).map(|v| v.parse::<u32>()).transpose();
+        let (hour, minute) = match num(Field::Hhmm)? {
+            Some(hhmm) => (hhmm / 100, hhmm % 100),
+            None => (
+                num(Field::Hour)?.unwrap_or_default(),
+                num(Field::Minute)?.unwrap_or_default(),
+            ),
+        };
+        let hwdt = HimawariDatetime::new(
+            num(Field::Year)?.unwrap_or_default() as i32,
+            num(Field::Month)?.unwrap_or_default(),
+            num(Field::Day)?.unwrap_or_default(),
+            hour,
+            minute,
+        )?;
+        let ext = match get(Field::Ext) {
+            Some(ext) => ext.to_string(),
+            None => path
+                .extension()
+                .map(|e| e.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        };
+
+        Ok(FrameName {
+            hwdt,
+            source: get(Field::Source).unwrap_or(SOURCE).into(),
+            product: get(Field::Product).unwrap_or(PRODUCT).into(),
+            level: get(Field::Level).unwrap_or(LEVEL).into(),
+            ext,
+        })
+    }
+}
+
+impl FromStr for FilenameTemplate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = vec![];
+        let mut rest = s;
+        while let Some(open) = rest.find('{') {
+            if open > 0 {
+                parts.push(Part::Literal(rest[..open].into()));
+            }
+            let close = rest[open..]
+                .find('}')
+                .ok_or_else(|| anyhow!("unclosed {{ in filename template {s}"))?;
+            let name = &rest[open + 1..open + close];
+            let field = Field::from_name(name)
+                .ok_or_else(|| anyhow!("unknown {{{name}}} in filename template {s}"))?;
+            parts.push(Part::Field(field));
+            rest = &rest[open + close + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.into()));
+        }
+
+        if s.starts_with('/') || s.split('/').any(|d| d.is_empty() || d == "..") {
+            bail!("filename template {s} must be relative to the output dir");
+        }
+        let has = |f: Field| parts.contains(&Part::Field(f));
+        if !(has(Field::Year)
+            && has(Field::Month)
+            && has(Field::Day)
+            && (has(Field::Hhmm) || (has(Field::Hour) && has(Field::Minute))))
+        {
+            bail!("filename template {s} needs {{year}} {{month}} {{day}} and {{hhmm}} (or {{hour}} and {{minute}})");
+        }
+        // Anything else wouldn't match what's written, or be seen by prune/reindex at all.
+        if !s.ends_with(".{ext}") {
+            bail!("filename template {s} must end in .{{ext}}");
+        }
+        for pair in parts.windows(2) {
+            if let [Part::Field(a), Part::Field(b)] = pair {
+                if a.width().is_none() && b.width().is_none() {
+                    bail!("filename template {s} needs something between {a:?} and {b:?}");
+                }
+            }
+        }
+
+        Ok(Self {
+            raw: s.into(),
+            parts,
+        })
+    }
+}
+
+impl TryFrom<String> for FilenameTemplate {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<FilenameTemplate> for String {
+    fn from(t: FilenameTemplate) -> Self {
+        t.raw
+    }
+}
+
+impl fmt::Display for FilenameTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name() -> FrameName {
+        FrameName::new(
+            "2022-09-21 00:10".parse().unwrap(),
+            &TileSource::default(),
+            "png",
+        )
+    }
+
+    #[test]
+    fn default_is_unchanged() {
+        let t = FilenameTemplate::default();
+        let p = t.render(&name());
+        assert_eq!(p, PathBuf::from("fulldisc-2022-09-21 00_10.png"));
+        assert_eq!(t.depth(), 0);
+        // Dashes in the parent dirs used to trip us up.
+        assert_eq!(
+            t.parse(&Path::new("/home/some-one/rust-wari/backup").join(p))
+                .unwrap(),
+            name()
+        );
+    }
+
+    #[test]
+    fn nested_round_trip() {
+        let t: FilenameTemplate = "{source}/{year}/{month}/{day}/{hhmm}_{level}.{ext}"
+            .parse()
+            .unwrap();
+        let mut n = name();
+        n.source = "corp-mirror".into();
+        n.ext = "jpg".into();
+
+        let p = t.render(&n);
+        assert_eq!(p, PathBuf::from("corp-mirror/2022/09/21/0010_20d.jpg"));
+        assert_eq!(t.depth(), 4);
+        assert_eq!(t.parse(&Path::new("/srv/some-dir").join(&p)).unwrap(), n);
+
+        assert!(t.parse(Path::new("2022/09/21/0010_20d.jpg")).is_err());
+        assert!(t.parse(Path::new("x/2022/09/21/0010-20d.jpg")).is_err());
+        assert!(t.parse(Path::new("x/2022/13/21/0010_20d.jpg")).is_err());
+    }
+
+    #[test]
+    fn bad_templates() {
+        for bad in [
+            "{year}-{month}-{day}.png",
+            "{year}{month}{day}{hhmm}{source}{level}",
+            "{year}{month}{day}{hhmm}.{nope}",
+            "/abs/{year}{month}{day}{hhmm}",
+            "../{year}{month}{day}{hhmm}",
+            "{year}{month}{day}{hhmm",
+            "{year}{month}{day}{hhmm}",
+            "{year}{month}{day}{hhmm}.png",
+            "{year}{month}{day}{hhmm}{ext}",
+            "{year}{month}{day}{hhmm}.{ext}/x",
+        ] {
+            assert!(bad.parse::<FilenameTemplate>().is_err(), "{bad}");
+        }
+    }
+}
@@ -1,8 +1,10 @@
+use crate::archive::Archive;
 use crate::user_config::Config;
 
 use anyhow::Result;
 use log::debug;
 use log::warn;
+use std::fs::File;
 use std::path::Path;
 use std::path::PathBuf;
 
@@ -22,39 +24,144 @@ pub async fn check_setup(uc: &Config) -> Result<()> {
     }
     if !exists(Path::new(&uc.backup).to_path_buf()).await {
         warn!("dir to store backup files does not exist, creating.");
-        std::fs::create_dir_all(&uc.completed).unwrap();
+        std::fs::create_dir_all(&uc.backup).unwrap();
     }
     debug!(".yaml is good!");
 
+    let depth = uc.filename.depth();
+    remove_stale_tmp_files(&uc.completed, depth)?;
+    remove_stale_tmp_files(&uc.backup, depth)?;
+
     Ok(())
 }
 
 /// Move the conetents of completed to the location specifed in the config.yml
 pub fn move_completed_to_backup(path: PathBuf, uc: &Config) -> Result<()> {
-    std::fs::copy(
-        &path,
-        Path::new(&uc.backup).join(path.file_name().expect("Unable to view file name.")),
-    )?;
+    // Laid out the same way in `backup` as it was in `completed`.
+    let dest = match uc.filename.parse(&path) {
+        Ok(name) => Path::new(&uc.backup).join(uc.filename.render(&name)),
+        Err(_) => Path::new(&uc.backup).join(path.file_name().expect("Unable to view file name.")),
+    };
+
+    let (tmp, mut fd) = AtomicFile::create(&dest)?;
+    std::io::copy(&mut File::open(&path)?, &mut fd)?;
+    drop(fd);
+    tmp.commit()?;
+    Archive::from_config(uc).record_file(&dest);
+
     debug!("Files backed up!");
     Ok(())
 }
+
+/// Suffix given to in-flight writes, anything still wearing it at startup is from a crash.
+pub const TMP_SUFFIX: &str = ".rustwari-tmp";
+
+/// A temp file in the same directory as `dest` which, once [`AtomicFile::commit`]ed is fsynced and
+/// renamed over the top of `dest`. This way whatever's at `dest` is always a complete image, even if
+/// we crash (or lose power) mid-write.
+/// If it's dropped without being committed the temp file is removed.
+#[derive(Debug)]
+pub struct AtomicFile {
+    tmp: PathBuf,
+    dest: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    /// Creates the temp file, write to the returned [`File`] then call [`AtomicFile::commit`].
+    pub fn create<P: AsRef<Path>>(dest: P) -> Result<(Self, File)> {
+        let dest = dest.as_ref().to_path_buf();
+        let name = dest
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("{} has no file name", dest.display()))?
+            .to_string_lossy();
+        let tmp = dest.with_file_name(format!(".{name}.{}{TMP_SUFFIX}", std::process::id()));
+
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let fd = File::create(&tmp)?;
+        Ok((
+            Self {
+                tmp,
+                dest,
+                committed: false,
+            },
+            fd,
+        ))
+    }
+
+    /// Flush the temp file to disk and move it into place.
+    /// NOTE: all handles to the [`File`] should be dropped (flushing any buffers) before calling this.
+    pub fn commit(mut self) -> Result<()> {
+        File::open(&self.tmp)?.sync_all()?;
+        std::fs::rename(&self.tmp, &self.dest)?;
+        self.committed = true;
+
+        // The rename itself isn't durable until the directory is synced too.
+        #[cfg(unix)]
+        if let Some(parent) = self.dest.parent() {
+            let parent = match parent.as_os_str().is_empty() {
+                true => Path::new("."),
+                false => parent,
+            };
+            File::open(parent)?.sync_all()?;
+        }
+
+        debug!("Committed: {}", self.dest.display());
+        Ok(())
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            _ = std::fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+/// Remove any temp files left behind in `dir` (or up to `depth` directories beneath it, as with
+/// [`images_in`]) by a run that didn't finish.
+pub fn remove_stale_tmp_files<P: AsRef<Path>>(dir: P, depth: usize) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let p = entry?.path();
+        if p.to_string_lossy().ends_with(TMP_SUFFIX) {
+            warn!("Removing stale temp file: {}", p.display());
+            std::fs::remove_file(p)?;
+        } else if depth > 0 && p.is_dir() {
+            remove_stale_tmp_files(&p, depth - 1)?;
+        }
+    }
+    Ok(())
+}
 pub async fn exists(path: PathBuf) -> bool {
     tokio::fs::metadata(&path).await.is_ok()
 }
-pub fn cleanup(uc: &crate::user_config::Config) -> Result<(), std::io::Error> {
-    debug!("Cleanup requested!");
-
-    std::fs::read_dir(&uc.completed)?.for_each(|path| {
-        let pb = path.expect("unable to make PathBuf from DirEntry").path();
-        if pb
-            .extension()
-            .unwrap_or_else(|| panic!("failed to get extension from file:{pb:#?}"))
-            == "png"
-        {
-            _ = std::fs::remove_file(pb);
+/// Every image `depth` directories beneath `dir`, where a [`crate::filenames::FilenameTemplate`]
+/// of that depth puts them.
+pub fn images_in<P: AsRef<Path>>(dir: P, depth: usize) -> Result<Vec<PathBuf>> {
+    let mut found = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        match depth {
+            0 if path.is_file() && is_image(&path) => found.push(path),
+            0 => {}
+            _ if path.is_dir() => found.extend(images_in(&path, depth - 1)?),
+            _ => {}
         }
-    });
-    Ok(())
+    }
+    Ok(found)
+}
+
+/// Does this look like one of the images we write?
+pub fn is_image<P: AsRef<Path>>(p: P) -> bool {
+    p.as_ref()
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| {
+            ["png", "jpg", "jpeg", "webp", "avif"].contains(&e.to_ascii_lowercase().as_str())
+        })
 }
 
 #[cfg(target_os = "macos")]
@@ -65,3 +172,58 @@ fn increase_ulimit() {
         .status()
         .expect("Failed to increase ulimit");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn atomic_file_only_appears_on_commit() {
+        let dir = std::env::temp_dir().join("rustwari_atomic_file_only_appears_on_commit");
+        std::fs::create_dir_all(&dir).unwrap();
+        let dest = dir.join("fulldisc.png");
+        _ = std::fs::remove_file(&dest);
+
+        let (tmp, mut fd) = AtomicFile::create(&dest).unwrap();
+        fd.write_all(b"not a real png").unwrap();
+        drop(fd);
+        assert!(!dest.exists());
+        tmp.commit().unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"not a real png");
+
+        // Abandoned writes are cleaned up, and never touch the destination.
+        let (tmp, mut fd) = AtomicFile::create(&dest).unwrap();
+        fd.write_all(b"truncat").unwrap();
+        drop((tmp, fd));
+        assert_eq!(std::fs::read(&dest).unwrap(), b"not a real png");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stale_tmp_files_are_removed() {
+        let dir = std::env::temp_dir().join("rustwari_stale_tmp_files_are_removed");
+        let nested = dir.join("2022").join("09");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::write(dir.join("keep.png"), b"").unwrap();
+        std::fs::write(dir.join(format!(".keep.png.1234{TMP_SUFFIX}")), b"").unwrap();
+        std::fs::write(nested.join(format!(".21.png.1234{TMP_SUFFIX}")), b"").unwrap();
+
+        // Not deep enough for a {year}/{month}/... template.
+        remove_stale_tmp_files(&dir, 1).unwrap();
+        assert_eq!(std::fs::read_dir(&nested).unwrap().count(), 1);
+
+        remove_stale_tmp_files(&dir, 2).unwrap();
+        let left = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(left.len(), 2);
+        assert!(left.contains(&"keep.png".into()));
+        assert_eq!(std::fs::read_dir(&nested).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,118 +1,320 @@
-use anyhow::Error;
-use anyhow::Result;
-use chrono::NaiveDateTime;
-use chrono::{Datelike, Timelike, Utc};
+use anyhow::{anyhow, bail, Error, Result};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
 use log::debug;
-use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::{Add, Sub};
 use std::path::Path;
+use std::str::FromStr;
 use url::Url;
 
-const URLBASE: &str = r#"https://himawari8.nict.go.jp/img/D531106/20d/550/"#;
-
-/// The structure holding all the temporal info about a tile
-/// used to generate filenames and [`url`]
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-pub struct HimawariDatetime {
-    //TODO: these would all fit in a u8...
-    pub year: u32, // 2015 to now *NOTE:2015 July 7th 9:50 is the first day available from the dataset
-    pub month: u32, // 1-12
-    pub day: u32,  // 1-31
-    pub h: u32,    // 0 to 23
-    pub m: u32, // can only increment from 0 to 50, by 10 at time *NOTE:1640 never works so strip it?
+pub const URLBASE: &str = r#"https://himawari8.nict.go.jp/img/D531106/20d/550/"#;
+
+/// The satellite, and product of it, that [`URLBASE`] points at.
+pub const SOURCE: &str = "himawari8";
+pub const PRODUCT: &str = "D531106";
+/// ..at this resolution level, a 20x20 grid of tiles.
+pub const LEVEL: &str = "20d";
+
+/// A new disc every this many minutes.
+pub const CADENCE_MINUTES: u32 = 10;
+
+/// The formats [`HimawariDatetime::from_str`] understands, the first is what [`fmt::Display`] gives.
+const FORMATS: [&str; 4] = [
+    "%Y-%m-%d %H:%M",
+    "%Y-%m-%dT%H:%M",
+    "%Y%m%d%H%M",
+    "%Y-%m-%d %H_%M",
+];
+
+/// The first disc in the dataset, 2015-07-07 01:50 UTC.
+pub fn dataset_start() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2015, 7, 7, 1, 50, 0)
+        .single()
+        .expect("a valid datetime")
 }
+
+/// When a disc was taken, always UTC, on the 10 minute cadence and within the dataset.
+/// Used to generate filenames and [`url`]s.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct HimawariDatetime(DateTime<Utc>);
+
 impl HimawariDatetime {
-    /// Constructs a [`HimawariDatetime`]
-    pub async fn new(year: u32, month: u32, day: u32, h: u32, m: u32) -> Self {
-        let now = Utc::now();
-        if year <= 2015 && month < 8 // date is outside the dataset
-            || year > now.year() as u32  // date is in the future
-            || month > 12
-            || day > 31
-            || h > 24
-            || m > 60
-        {
-            error!("Invalid datetime, returning default, which is the first day of operation available in the dataset.");
-            error!("YOU ENTERED:{}/{}/{} {}:{}", year, month, day, h, m);
-            warn!("The earliest possible HimawariDatetime will be created for you.");
-            return Self {
-                year: 2015,
-                month: 7,
-                day: 7,
-                h: 1,
-                m: 50,
-            };
-        };
+    /// Constructs a [`HimawariDatetime`], the minute is snapped down to the 10 minute cadence.
+    pub fn new(year: i32, month: u32, day: u32, h: u32, m: u32) -> Result<Self> {
+        let dt = NaiveDate::from_ymd_opt(year, month, day)
+            .and_then(|d| d.and_hms_opt(h, m, 0))
+            .ok_or_else(|| {
+                anyhow!("{year}-{month:02}-{day:02} {h:02}:{m:02} isn't a valid datetime")
+            })?;
+        Self::try_from(dt.and_utc())
+    }
 
-        // clamp the minutes
-        let m: u32 = now.minute() - (now.minute() % 20);
+    /// Constructs a [`HimawariDatetime`] a little before your current time, so it's (very likely)
+    /// been published.
+    pub fn closest_to_now() -> Self {
+        Self::closest_to(Utc::now())
+    }
 
-        Self {
-            year,
-            month,
-            day,
-            h,
-            m,
-        }
+    /// [`HimawariDatetime::closest_to_now`], were it `now`.
+    pub fn closest_to(now: DateTime<Utc>) -> Self {
+        let now = now - Duration::minutes(260);
+        debug!("closest_to_now() set at: {}", &now);
+        Self(snap(now))
     }
-    /// Helper to build pretty filenames for complete disks
-    pub fn pretty_filename(&self) -> String {
-        format!(
-            "fulldisc-{}-{:02}-{:02} {:02}_{:02}.png",
-            self.year, self.month, self.day, self.h, self.m
-        )
+
+    pub fn datetime(&self) -> DateTime<Utc> {
+        self.0
+    }
+    pub fn year(&self) -> i32 {
+        self.0.year()
+    }
+    pub fn month(&self) -> u32 {
+        self.0.month()
+    }
+    pub fn day(&self) -> u32 {
+        self.0.day()
     }
+    pub fn hour(&self) -> u32 {
+        self.0.hour()
+    }
+    pub fn minute(&self) -> u32 {
+        self.0.minute()
+    }
+
+    /// Helper to build pretty filenames for complete disks, `ext` should match the encoder used.
+    pub fn pretty_filename(&self, ext: &str) -> String {
+        format!("fulldisc-{}.{ext}", self.0.format("%Y-%m-%d %H_%M"))
+    }
+
     /// Helper to get a HWDT back out of an existing file, usually used on a fulldisc
     pub fn from_path(path: &Path) -> Result<Self, Error> {
-        let p = path.to_str().expect("unable to parse PathBuf");
-
-        // example filename : fulldisc-2022-2-3 0_30.png
-        let p_split = p.split('-').collect::<Vec<&str>>();
-        let year = p_split[1].parse::<u32>()?;
-        let month = p_split[2].parse::<u32>()?;
-        let day = p_split[3].parse::<u32>()?;
-        let h_m = p_split[4].split('_').collect::<Vec<&str>>();
-        let h = h_m[0].parse::<u32>()?;
-        let m = h_m[1].parse::<u32>()?;
-
-        Ok(Self {
-            year,
-            month,
-            day,
-            h,
-            m,
-        })
-    }
-
-    /// Constructs a [`HimawariDatetime`] closest to your current time, minus 20 minutes.
-    pub fn closest_to_now() -> Self {
-        let now = Utc::now() - chrono::Duration::minutes(260);
-        debug!("closest_to_now() set at: {}", &now);
-        Self {
-            year: now.year() as u32,
-            month: now.month(),
-            day: now.day(),
-            h: now.hour(),
-            m: now.minute() - (now.minute() % 10),
-        }
+        // example filename : fulldisc-2022-02-03 00_30.png, only the name is looked at.
+        let stem = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or_else(|| anyhow!("unable to get a file name from {}", path.display()))?;
+        stem.trim_start_matches("fulldisc-").parse()
     }
-    /// builds valid [`url`]
+
+    /// builds valid [`url`], from NICT, see [`crate::sources::TileSource`] for anywhere else.
     pub fn get_url(&self, x: u32, y: u32) -> Result<Url> {
         let url = Url::parse(&format!(
-            "{}{}/{:02}/{:02}/{:02}{:02}00_{}_{}.png",
-            URLBASE, self.year, self.month, self.day, self.h, self.m, x, y
+            "{}{}00_{}_{}.png",
+            URLBASE,
+            self.0.format("%Y/%m/%d/%H%M"),
+            x,
+            y
         ))?;
 
         Ok(url)
     }
 }
-impl From<NaiveDateTime> for HimawariDatetime {
-    fn from(dt: NaiveDateTime) -> Self {
+
+/// Rounds down to the 10 minute cadence.
+fn snap(dt: DateTime<Utc>) -> DateTime<Utc> {
+    dt.with_minute(dt.minute() - dt.minute() % CADENCE_MINUTES)
+        .and_then(|dt| dt.with_second(0))
+        .and_then(|dt| dt.with_nanosecond(0))
+        .expect("rounding down keeps it valid")
+}
+
+impl TryFrom<DateTime<Utc>> for HimawariDatetime {
+    type Error = Error;
+
+    fn try_from(dt: DateTime<Utc>) -> Result<Self> {
+        let dt = snap(dt);
+        if dt < dataset_start() {
+            bail!("{dt} is before the dataset starts ({})", dataset_start());
+        }
+        if dt > Utc::now() {
+            bail!("{dt} is in the future");
+        }
+        Ok(Self(dt))
+    }
+}
+
+impl TryFrom<NaiveDateTime> for HimawariDatetime {
+    type Error = Error;
+
+    /// `dt` is taken to be UTC.
+    fn try_from(dt: NaiveDateTime) -> Result<Self> {
+        Self::try_from(dt.and_utc())
+    }
+}
+
+impl FromStr for HimawariDatetime {
+    type Err = Error;
+
+    /// `2022-09-21 00:10`, `2022-09-21T00:10` or `202209210010`, all UTC.
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let dt = FORMATS
+            .iter()
+            .find_map(|fmt| NaiveDateTime::parse_from_str(s, fmt).ok())
+            .ok_or_else(|| anyhow!("Unable to parse {s}, try YYYY-MM-DD HH:MM"))?;
+        Self::try_from(dt)
+    }
+}
+
+impl TryFrom<String> for HimawariDatetime {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<HimawariDatetime> for String {
+    fn from(hwdt: HimawariDatetime) -> Self {
+        hwdt.to_string()
+    }
+}
+
+impl fmt::Display for HimawariDatetime {
+    /// YYYY-MM-DD HH:MM, in UTC.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.format(FORMATS[0]))
+    }
+}
+
+/// The result is snapped to the cadence but, unlike the constructors, not checked against the
+/// dataset's bounds.
+impl Add<Duration> for HimawariDatetime {
+    type Output = Self;
+
+    fn add(self, rhs: Duration) -> Self {
+        Self(snap(self.0 + rhs))
+    }
+}
+
+impl Sub<Duration> for HimawariDatetime {
+    type Output = Self;
+
+    fn sub(self, rhs: Duration) -> Self {
+        Self(snap(self.0 - rhs))
+    }
+}
+
+/// Every frame from `start` to `end` (inclusive) `step` apart, like [`std::ops::Range`] iterating
+/// moves `start` along.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HimawariRange {
+    pub start: HimawariDatetime,
+    pub end: HimawariDatetime,
+    pub step: Duration,
+}
+
+impl HimawariRange {
+    /// `step` must be a (positive) multiple of the 10 minute cadence.
+    pub fn new(start: HimawariDatetime, end: HimawariDatetime, step: Duration) -> Result<Self> {
+        if step.num_minutes() <= 0 || step.num_minutes() % CADENCE_MINUTES as i64 != 0 {
+            bail!("{step} is not a multiple of {CADENCE_MINUTES} minutes");
+        }
+        if start > end {
+            bail!("{start} is after {end}");
+        }
+        Ok(Self { start, end, step })
+    }
+
+    /// Just `hwdt`.
+    pub fn single(hwdt: HimawariDatetime) -> Self {
         Self {
-            year: dt.year() as u32,
-            month: dt.month(),
-            day: dt.day(),
-            h: dt.hour(),
-            m: dt.minute(),
+            start: hwdt,
+            end: hwdt,
+            step: Duration::minutes(CADENCE_MINUTES as i64),
+        }
+    }
+}
+
+impl Iterator for HimawariRange {
+    type Item = HimawariDatetime;
+
+    fn next(&mut self) -> Option<HimawariDatetime> {
+        if self.start > self.end {
+            return None;
         }
+        let t = self.start;
+        self.start = self.start + self.step;
+        Some(t)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = match self.start > self.end {
+            true => 0,
+            false => {
+                ((self.end.0 - self.start.0).num_minutes() / self.step.num_minutes()) as usize + 1
+            }
+        };
+        (n, Some(n))
+    }
+}
+
+impl ExactSizeIterator for HimawariRange {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validation() {
+        let hwdt = HimawariDatetime::new(2022, 9, 21, 0, 17).unwrap();
+        assert_eq!(hwdt.to_string(), "2022-09-21 00:10");
+
+        // Previously rejected, as it's January.
+        assert!(HimawariDatetime::new(2016, 1, 5, 12, 0).is_ok());
+        assert!(HimawariDatetime::new(2015, 7, 7, 1, 50).is_ok());
+        assert!(HimawariDatetime::new(2015, 7, 7, 1, 40).is_err());
+        assert!(HimawariDatetime::new(2022, 9, 21, 24, 0).is_err());
+        assert!(HimawariDatetime::new(2022, 9, 21, 0, 60).is_err());
+        assert!(HimawariDatetime::new(2022, 2, 30, 0, 0).is_err());
+        assert!(HimawariDatetime::try_from(Utc::now() + Duration::days(1)).is_err());
+    }
+
+    #[test]
+    fn parsing_ordering_and_arithmetic() {
+        let a: HimawariDatetime = "2022-09-21 00:10".parse().unwrap();
+        assert_eq!(a, "2022-09-21T00:10".parse().unwrap());
+        assert_eq!(a, "202209210010".parse().unwrap());
+        assert!("yesterday".parse::<HimawariDatetime>().is_err());
+
+        let b = a + Duration::minutes(25);
+        assert_eq!(b.to_string(), "2022-09-21 00:30");
+        assert!(a < b);
+        assert_eq!(b - Duration::minutes(20), a);
+
+        assert_eq!(serde_json::to_string(&a).unwrap(), r#""2022-09-21 00:10""#);
+        assert_eq!(
+            serde_json::from_str::<HimawariDatetime>(r#""2022-09-21 00:10""#).unwrap(),
+            a
+        );
+
+        let r = HimawariRange::new(
+            a,
+            "2022-09-21 01:10".parse().unwrap(),
+            Duration::minutes(20),
+        )
+        .unwrap();
+        assert_eq!(r.len(), 4);
+        assert_eq!(
+            r.map(|t| t.to_string()).collect::<Vec<_>>(),
+            vec![
+                "2022-09-21 00:10",
+                "2022-09-21 00:30",
+                "2022-09-21 00:50",
+                "2022-09-21 01:10"
+            ]
+        );
+        assert_eq!(HimawariRange::single(a).collect::<Vec<_>>(), vec![a]);
+        assert!(HimawariRange::new(b, a, Duration::minutes(10)).is_err());
+        assert!(HimawariRange::new(a, b, Duration::minutes(15)).is_err());
+
+        assert_eq!(a.pretty_filename("png"), "fulldisc-2022-09-21 00_10.png");
+        assert_eq!(
+            HimawariDatetime::from_path(Path::new("/some-dir/fulldisc-2022-09-21 00_10.png"))
+                .unwrap(),
+            a
+        );
     }
 }
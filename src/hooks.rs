@@ -0,0 +1,243 @@
+//! Your own commands, run before a disc is fetched, once it's saved and once it's set, see the
+//! `hooks:` section of the config.yml
+
+use crate::himawaridt::HimawariDatetime;
+use crate::wallpaperutils::FullDisc;
+
+use anyhow::{anyhow, bail, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::process::Command;
+
+/// When, in a run, a hook is run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Before anything's fetched.
+    PreFetch,
+    /// The disc is on disk, but not yet the wallpaper.
+    PostSave,
+    /// The disc is the wallpaper.
+    PostSet,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::PreFetch => "pre_fetch",
+            Self::PostSave => "post_save",
+            Self::PostSet => "post_set",
+        })
+    }
+}
+
+/// What happens when a hook exits non-zero, or runs out of time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnFailure {
+    /// Log it and carry on.
+    #[default]
+    Warn,
+    /// Carry on without a word (well, a debug one).
+    Ignore,
+    /// Stop the run there, before a pre_fetch or post_save hook that means keeping your current
+    /// wallpaper.
+    Abort,
+}
+
+/// A single command, run with `sh -c` (`cmd /C` on windows).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hook {
+    pub command: String,
+    /// It's killed (and counted as failed) after this long.
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    #[serde(default)]
+    pub on_failure: OnFailure,
+}
+
+fn default_timeout_secs() -> u64 {
+    30
+}
+
+/// Lives under `hooks:` in the config.yml, each stage's hooks are run in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HookSettings {
+    pub pre_fetch: Vec<Hook>,
+    pub post_save: Vec<Hook>,
+    pub post_set: Vec<Hook>,
+}
+
+/// What the hooks are told about the run, as `RUSTWARI_*` environment variables.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookEnv {
+    pub hwdt: HimawariDatetime,
+    /// The [`crate::wallpaperutils::WallpaperBackend`]'s name.
+    pub backend: String,
+    /// Where the disc is, and its width & height, unknown before it's fetched.
+    pub image: Option<(PathBuf, u32, u32)>,
+}
+
+impl HookEnv {
+    pub fn new(hwdt: HimawariDatetime, backend: &str) -> Self {
+        Self {
+            hwdt,
+            backend: backend.into(),
+            image: None,
+        }
+    }
+
+    /// The same, but with `fulldisc` (and its timestamp, should it be from a peer) filled in.
+    pub fn with_disc(&self, fulldisc: &FullDisc, hwdt: HimawariDatetime) -> Self {
+        Self {
+            hwdt,
+            backend: self.backend.clone(),
+            image: Some((fulldisc.path.clone(), fulldisc.width, fulldisc.height)),
+        }
+    }
+
+    pub fn vars(&self, stage: Stage) -> Vec<(&'static str, String)> {
+        let mut vars = vec![
+            ("RUSTWARI_HOOK", stage.to_string()),
+            ("RUSTWARI_TIMESTAMP", self.hwdt.to_string()),
+            ("RUSTWARI_TIMESTAMP_ISO", self.hwdt.datetime().to_rfc3339()),
+            ("RUSTWARI_BACKEND", self.backend.clone()),
+        ];
+        if let Some((path, width, height)) = &self.image {
+            vars.extend([
+                ("RUSTWARI_IMAGE", path.display().to_string()),
+                ("RUSTWARI_WIDTH", width.to_string()),
+                ("RUSTWARI_HEIGHT", height.to_string()),
+                ("RUSTWARI_RESOLUTION", format!("{width}x{height}")),
+            ]);
+        }
+        vars
+    }
+}
+
+impl HookSettings {
+    pub fn for_stage(&self, stage: Stage) -> &[Hook] {
+        match stage {
+            Stage::PreFetch => &self.pre_fetch,
+            Stage::PostSave => &self.post_save,
+            Stage::PostSet => &self.post_set,
+        }
+    }
+
+    /// Run the hooks for `stage`, an `Err` means one that's `on_failure: abort` failed.
+    pub async fn run(&self, stage: Stage, env: &HookEnv) -> Result<()> {
+        for hook in self.for_stage(stage) {
+            let Err(e) = hook.run(stage, env).await else {
+                continue;
+            };
+            match hook.on_failure {
+                OnFailure::Ignore => debug!("{stage} hook failed: {e}"),
+                OnFailure::Warn => warn!("{stage} hook failed: {e}"),
+                OnFailure::Abort => bail!("{stage} hook failed: {e}"),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Hook {
+    async fn run(&self, stage: Stage, env: &HookEnv) -> Result<()> {
+        #[cfg(windows)]
+        let mut cmd = {
+            let mut cmd = Command::new("cmd");
+            cmd.arg("/C");
+            cmd
+        };
+        #[cfg(not(windows))]
+        let mut cmd = {
+            let mut cmd = Command::new("sh");
+            cmd.arg("-c");
+            cmd
+        };
+        cmd.arg(&self.command)
+            .envs(env.vars(stage))
+            .kill_on_drop(true);
+
+        debug!("Running {stage} hook: {}", self.command);
+        let output = tokio::time::timeout(Duration::from_secs(self.timeout_secs), cmd.output())
+            .await
+            .map_err(|_| anyhow!("`{}` timed out after {}s", self.command, self.timeout_secs))??;
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if !stdout.trim().is_empty() {
+            debug!("{stage} hook said: {}", stdout.trim());
+        }
+        match output.status.success() {
+            true => Ok(()),
+            false => bail!(
+                "`{}` exited with {}: {}",
+                self.command,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn hook(command: &str, on_failure: OnFailure) -> Hook {
+        Hook {
+            command: command.into(),
+            timeout_secs: 1,
+            on_failure,
+        }
+    }
+
+    fn env() -> HookEnv {
+        HookEnv {
+            hwdt: "2022-09-21 00:10".parse().unwrap(),
+            backend: "system".into(),
+            image: Some(("/tmp/disc.png".into(), 5500, 5500)),
+        }
+    }
+
+    #[tokio::test]
+    async fn hooks_get_the_run() {
+        let out = std::env::temp_dir().join("rustwari_hooks_get_the_run");
+        let settings = HookSettings {
+            post_set: vec![hook(
+                &format!(
+                    "echo \"$RUSTWARI_HOOK $RUSTWARI_TIMESTAMP $RUSTWARI_RESOLUTION $RUSTWARI_BACKEND $RUSTWARI_IMAGE\" > {}",
+                    out.display()
+                ),
+                OnFailure::Abort,
+            )],
+            ..Default::default()
+        };
+        settings.run(Stage::PostSet, &env()).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&out).unwrap().trim(),
+            "post_set 2022-09-21 00:10 5500x5500 system /tmp/disc.png"
+        );
+        std::fs::remove_file(out).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failures() {
+        let run = |command: &str, on_failure| {
+            let settings = HookSettings {
+                pre_fetch: vec![hook(command, on_failure)],
+                ..Default::default()
+            };
+            async move { settings.run(Stage::PreFetch, &env()).await }
+        };
+        assert!(run("exit 1", OnFailure::Warn).await.is_ok());
+        assert!(run("exit 1", OnFailure::Ignore).await.is_ok());
+        assert!(run("exit 1", OnFailure::Abort).await.is_err());
+        assert!(run("true", OnFailure::Abort).await.is_ok());
+
+        let slow = run("sleep 5", OnFailure::Abort).await.unwrap_err();
+        assert!(slow.to_string().contains("timed out"), "{slow}");
+    }
+}
@@ -2,11 +2,30 @@
 //! if you wanted to use this as a library it'd probs work..
 //!
 
+pub mod archive;
+pub mod backfill;
 pub mod cli;
 pub mod cvutils;
+pub mod daylight;
+pub mod filenames;
 pub mod fileutils;
 pub mod himawaridt;
+pub mod hooks;
+pub mod metrics;
+pub mod netutils;
+pub mod outputs;
+pub mod palette;
+pub mod peer;
+pub mod plan;
+pub mod preview;
+pub mod progress;
+pub mod retention;
+pub mod server;
+pub mod sources;
 pub mod termite;
 pub mod tiles;
+pub mod timelapse;
+pub mod update;
 pub mod user_config;
 pub mod wallpaperutils;
+pub mod when;
@@ -1,55 +1,56 @@
 #![allow(unreachable_patterns)]
 
 use anyhow::Result;
-use chrono::NaiveDateTime;
-use log::debug;
+use chrono::Utc;
+use log::{debug, info, warn};
 use open::that;
 use reqwest::Client;
-use tokio::sync::mpsc;
-
-use rustwari::cli::Cli;
-use rustwari::cvutils::assemble_full_disc;
-use rustwari::fileutils::{check_setup, move_completed_to_backup};
-use rustwari::himawaridt::HimawariDatetime;
-use rustwari::termite::setup_logger;
-use rustwari::tiles::fetch_full_disc;
+
+use rustwari::archive::{Archive, Problem};
+use rustwari::backfill::{backfill, BackfillOpts, STATE_FILE};
+use rustwari::cli::{ArchiveCommand, Cli, Command};
+use rustwari::daylight::choose;
+use rustwari::fileutils::check_setup;
+use rustwari::himawaridt::HimawariRange;
+use rustwari::metrics::serve_metrics;
+use rustwari::netutils::build_client;
+use rustwari::outputs::shrunk_disc;
+use rustwari::palette::export_for;
+use rustwari::plan::plan;
+use rustwari::preview::{find_disc, render};
+use rustwari::progress::set_sink;
+use rustwari::retention::prune;
+use rustwari::server::{refresh, reload_frames, serve_http, ServeOpts, SharedFrames, SharedLatest};
+use rustwari::sources::TileSource;
+use rustwari::termite::{level_from, setup_logger};
+use rustwari::tiles::{FetchBudget, TooManyMissing};
+use rustwari::timelapse::{timelapse, TimelapseOpts};
+use rustwari::update::{fetch_and_assemble, update_wallpaper};
 use rustwari::user_config::{Config, USERCONFIG};
-use rustwari::wallpaperutils::FullDisc;
+use rustwari::wallpaperutils::default_backend;
+use rustwari::when::{parse_every, parse_when};
 
 async fn run(client: &Client, uc: &Config, cli: &Cli) -> Result<()> {
     let t1 = std::time::Instant::now();
-    let hwdt = HimawariDatetime::closest_to_now();
-    let (tx, rx) = mpsc::channel(400);
-
-    let handles = fetch_full_disc(client, hwdt, tx).await?;
-
-    for h in handles
-        .lock()
-        .expect("Unable to lock joinhandles")
-        .drain(..)
-    {
-        h.await?;
-    }
-
-    let mut fulldisc: FullDisc = assemble_full_disc(hwdt, uc, cli, rx).await?;
-
-    if cli.resize {
-        fulldisc.resize_this(5120, 5120)?; //TODO: const
-    }
 
-    #[cfg(not(feature = "hypr"))]
-    fulldisc.set_this()?;
-
-    #[cfg(feature = "hypr")]
-    fulldisc.set_with_hyprpaper();
-
-    if cli.backup {
-        move_completed_to_backup(fulldisc.path, uc)?;
+    let res = update_wallpaper(
+        client,
+        uc,
+        cli,
+        choose(&uc.daylight, Utc::now()),
+        default_backend().as_ref(),
+    )
+    .await;
+    match res {
+        Ok(_) => {}
+        // A rejected disc just means keeping the current wallpaper a while longer.
+        Err(e) if e.downcast_ref::<TooManyMissing>().is_some() => {
+            warn!("{e}, keeping the current wallpaper")
+        }
+        Err(e) => return Err(e),
     }
 
-    if cli.verbose {
-        debug!("{}", t1.elapsed().as_secs_f64());
-    }
+    debug!("{}", t1.elapsed().as_secs_f64());
 
     std::thread::sleep(std::time::Duration::from_secs(601));
     Ok(())
@@ -57,22 +58,68 @@ async fn run(client: &Client, uc: &Config, cli: &Cli) -> Result<()> {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let client = Client::new();
     let cli = Cli::init();
+    set_sink(cli.progress.sink());
+
+    //Setup, nothing's created (or tidied, logged to a file, or listened on) for a dry run.
+    let uc = match cli.dry_run && !std::path::Path::new(USERCONFIG).is_file() {
+        true => Config::defaults(),
+        false => {
+            Config::check_config_exits(USERCONFIG).expect("User Config doesn't exist :(");
+            Config::new_from_yaml(USERCONFIG)?
+        }
+    };
+    let client = build_client(&uc.http)?;
 
-    //Setup
-    Config::check_config_exits(USERCONFIG).expect("User Config doesn't exist :(");
-    let uc = Config::new_from_yaml(USERCONFIG)?;
+    let mut logging = uc.logging.clone();
+    if cli.dry_run {
+        logging.dir = None;
+    }
+    setup_logger(level_from(cli.verbose, cli.quiet), &logging).expect("FAILED TO INIT LOGGER!");
+    debug!("{uc:#?}");
 
-    if cli.verbose {
-        setup_logger(true).expect("FAILED TO INIT LOGGER!");
-        debug!("{uc:#?}");
+    if !cli.dry_run {
+        check_setup(&uc).await.expect("Setup is borked...");
     }
 
-    check_setup(&uc).await.expect("Setup is borked...");
+    if let Some(addr) = uc.metrics.as_ref().filter(|_| !cli.dry_run) {
+        let addr = addr.parse()?;
+        tokio::spawn(async move {
+            if let Err(e) = serve_metrics(addr).await {
+                log::error!("Metrics listener failed: {e}");
+            }
+        });
+    }
 
-    if cli.oneshot.is_some() {
-        _ = run_oneshot(&client, &cli, &uc).await;
+    if let Some(Command::Prune { dry_run }) = cli.command {
+        let dry_run = dry_run || cli.dry_run;
+        for c in prune(&uc, dry_run)? {
+            match dry_run {
+                true => println!("would remove: {}", c.path.display()),
+                false => println!("removed: {}", c.path.display()),
+            }
+        }
+    } else if let Some(Command::Archive { action }) = &cli.command {
+        run_archive(action, &uc)?;
+    } else if let Some(Command::Backfill { .. }) = &cli.command {
+        run_backfill(&client, &cli, &uc).await?;
+    } else if let Some(Command::Timelapse { .. }) = &cli.command {
+        run_timelapse(&client, &cli, &uc).await?;
+    } else if let Some(Command::Preview { .. }) = &cli.command {
+        run_preview(&cli, &uc)?;
+    } else if let Some(Command::Serve { .. }) = &cli.command {
+        run_serve(client, cli.clone(), uc).await?;
+    } else if cli.dry_run {
+        let frames = match &cli.oneshot {
+            Some(when) => parse_when(when)?.collect(),
+            None => vec![choose(&uc.daylight, Utc::now())],
+        };
+        let backend = default_backend();
+        for line in plan(&uc, &cli, &frames, backend.as_ref(), cli.oneshot.is_none())? {
+            println!("{line}");
+        }
+    } else if cli.oneshot.is_some() {
+        run_oneshot(&client, &cli, &uc).await?;
     } else {
         // Run indefnitely.
         loop {
@@ -84,142 +131,198 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn run_oneshot(client: &Client, cli: &Cli, uc: &Config) -> Result<()> {
-    if let Some(oneshot_str) = cli.oneshot.clone() {
-        let hwdt = NaiveDateTime::parse_from_str(&oneshot_str, "%Y-%m-%d %H:%M").unwrap(); //_or_else(|e| panic!("{e:#?}\n{oneshot_str}"));
-
-        let (tx, rx) = mpsc::channel(400);
-
-        let handles = fetch_full_disc(client, hwdt.into(), tx).await?;
-
-        for h in handles
-            .lock()
-            .expect("Unable to lock joinhandles")
-            .drain(..)
-        {
-            h.await?;
+fn run_archive(action: &ArchiveCommand, uc: &Config) -> Result<()> {
+    let archive = Archive::from_config(uc);
+    match action {
+        ArchiveCommand::List => archive.entries()?.iter().for_each(print_entry),
+        ArchiveCommand::Find { query } => archive.find(query)?.iter().for_each(print_entry),
+        ArchiveCommand::Verify => {
+            let problems = archive.verify()?;
+            for (e, problem) in problems.iter() {
+                match problem {
+                    Problem::Missing => println!("MISSING {}", e.path.display()),
+                    Problem::ChecksumMismatch { actual } => println!(
+                        "CHECKSUM MISMATCH {} expected:{} actual:{actual}",
+                        e.path.display(),
+                        e.sha256
+                    ),
+                }
+            }
+            if !problems.is_empty() {
+                anyhow::bail!("{} archived images failed verification", problems.len());
+            }
         }
-
-        // Set that badboy as your wallpaper.
-        let mut fulldisc: FullDisc = assemble_full_disc(hwdt.into(), uc, cli, rx).await?;
-
-        if cli.resize {
-            fulldisc.resize_this(5120, 5120)?;
+        ArchiveCommand::Reindex => {
+            let n = archive.reindex(&[&uc.completed, &uc.backup])?;
+            println!("Reindexed {n} images into {}", archive.path.display());
         }
-
-        that(fulldisc.path)?;
-    };
-
+    }
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rustwari::tiles::RemoteTile;
-
-    use bytes::Bytes;
-    use std::fs::File;
-    use std::io::prelude::*;
-    use std::path::Path;
-
-    fn setup() -> (
-        Cli,
-        Client,
-        Config,
-        (
-            mpsc::Sender<(Bytes, RemoteTile)>,
-            mpsc::Receiver<(Bytes, RemoteTile)>,
-        ),
-    ) {
-        let cli = Cli {
-            quiet: false,
-            verbose: true,
-            completed_dir: None,
-            config_file: None,
-            resize: true,
-            open: false,
-            oneshot: None,
-            backup: false,
-        };
-
-        let client = Client::new();
+fn print_entry(e: &rustwari::archive::ArchiveEntry) {
+    println!(
+        "{}  {}x{}  {:>10}  {}",
+        e.timestamp,
+        e.width,
+        e.height,
+        e.size,
+        e.path.display()
+    );
+}
 
-        Config::check_config_exits(USERCONFIG).expect("User Config doesn't exist :(");
-        let uc = Config::new_from_yaml(USERCONFIG).unwrap();
+async fn run_backfill(client: &Client, cli: &Cli, uc: &Config) -> Result<()> {
+    let Some(Command::Backfill {
+        from,
+        to,
+        every,
+        tiles,
+        jobs,
+        max_connections,
+        rate,
+        retries,
+        state_file,
+    }) = cli.command.clone()
+    else {
+        return Ok(());
+    };
 
-        let (tx, rx) = mpsc::channel(400);
-        (cli, client, uc, (tx, rx))
+    let mut range = match to {
+        Some(to) => parse_when(&format!("{from}..{to}"))?,
+        None => parse_when(&from)?,
+    };
+    if let Some(every) = every {
+        range = HimawariRange::new(range.start, range.end, parse_every(&every)?)?;
     }
 
-    #[tokio::test]
-    async fn run_now() {
-        let (cli, client, uc, (tx, rx)) = setup();
-
-        let t1 = std::time::Instant::now();
-        let hwdt = HimawariDatetime::new(2018, 8, 18, 9, 0).await;
+    let opts = BackfillOpts {
+        range,
+        tiles,
+        jobs,
+        budget: FetchBudget::new(max_connections, rate, Some(retries)),
+        source: TileSource::from_config(uc)?,
+        state_file: state_file
+            .map(Into::into)
+            .unwrap_or_else(|| std::path::Path::new(&uc.backup).join(STATE_FILE)),
+    };
 
-        let handles = fetch_full_disc(&client, hwdt, tx).await.unwrap();
+    let state = backfill(client, uc, cli, &opts).await?;
+    // Not println, as stdout may be --progress json.
+    info!(
+        "Backfill finished, {} frames done, {} failed.",
+        state.done.len(),
+        state.failed.len()
+    );
+    Ok(())
+}
 
-        for h in handles
-            .lock()
-            .expect("Unable to lock joinhandles")
-            .drain(..)
-        {
-            h.await.unwrap();
-        }
+async fn run_timelapse(client: &Client, cli: &Cli, uc: &Config) -> Result<()> {
+    let Some(Command::Timelapse {
+        range,
+        out,
+        interpolation,
+        between,
+        size,
+    }) = cli.command.clone()
+    else {
+        return Ok(());
+    };
 
-        // Set that badboy as your wallpaper.
-        let mut fulldisc: FullDisc = assemble_full_disc(hwdt, &uc, &cli, rx).await.unwrap();
+    let opts = TimelapseOpts {
+        range: parse_when(&range)?,
+        out: out.into(),
+        interpolation,
+        between,
+        size,
+    };
+    let frames = timelapse(client, uc, cli, &opts).await?;
+    println!(
+        "Wrote {} frames to {}, i.e: ffmpeg -framerate 30 -i {}/frame_%05d.png timelapse.mp4",
+        frames.len(),
+        opts.out.display(),
+        opts.out.display()
+    );
+    Ok(())
+}
 
-        if cli.resize {
-            fulldisc.resize_this(5120, 5120).unwrap();
-        }
+fn run_preview(cli: &Cli, uc: &Config) -> Result<()> {
+    let Some(Command::Preview {
+        what,
+        protocol,
+        width,
+    }) = cli.command.clone()
+    else {
+        return Ok(());
+    };
 
-        fulldisc.set_this().unwrap();
+    let p = find_disc(uc, what.as_deref())?;
+    let cols = width.unwrap_or_else(|| kdam::term::get_columns_or(80));
+    print!("{}", render(&image::open(&p)?, protocol, cols as u32)?);
+    println!("{}", p.display());
+    Ok(())
+}
 
-        eprintln!("Elapsed: {}s", t1.elapsed().as_secs_f64());
-    }
+async fn run_serve(client: Client, cli: Cli, uc: Config) -> Result<()> {
+    let Some(Command::Serve {
+        bind,
+        size,
+        quality,
+    }) = cli.command.clone()
+    else {
+        return Ok(());
+    };
 
-    #[ignore] // This test requres pre-existing data.
-    #[tokio::test]
-    async fn oneshot_mode() {
-        let (cli, client, uc, (tx, rx)) = setup();
+    let opts = ServeOpts {
+        bind: bind.parse()?,
+        size,
+        quality,
+    };
+    let latest = SharedLatest::default();
+    let frames = SharedFrames::default();
+    let archive = Archive::from_config(&uc);
+    reload_frames(&archive, &frames).await?;
 
-        let oneshot_str = "2022-09-21 00:10";
-        let hwdt = NaiveDateTime::parse_from_str(oneshot_str, "%Y-%m-%d %H:%M")
-            .unwrap_or_else(|e| panic!("{e:#?}\n{oneshot_str}"));
+    // Keep the latest disc fresh in the background, a failed fetch just means serving the
+    // previous one a little longer.
+    {
+        let latest = latest.clone();
+        let frames = frames.clone();
+        let opts = opts.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = refresh(&client, &uc, &cli, &opts, &latest).await {
+                    log::error!("Unable to refresh the latest disc: {e}");
+                }
+                if let Err(e) = prune(&uc, false) {
+                    log::error!("Unable to prune: {e}");
+                }
+                if let Err(e) = reload_frames(&archive, &frames).await {
+                    log::error!("Unable to reload the archive: {e}");
+                }
+                tokio::time::sleep(std::time::Duration::from_secs(601)).await;
+            }
+        });
+    }
 
-        let handles = fetch_full_disc(&client, hwdt.into(), tx).await.unwrap();
+    serve_http(opts.bind, latest, frames).await
+}
 
-        for h in handles
-            .lock()
-            .expect("Unable to lock joinhandles")
-            .drain(..)
-        {
-            h.await.unwrap();
+async fn run_oneshot(client: &Client, cli: &Cli, uc: &Config) -> Result<()> {
+    if let Some(oneshot_str) = cli.oneshot.clone() {
+        // A range fetches every frame in it, opening only the last.
+        let mut last = None;
+        for hwdt in parse_when(&oneshot_str)? {
+            let fulldisc = fetch_and_assemble(client, uc, cli, hwdt).await?;
+            if let Some(shrunk) = shrunk_disc(uc, &fulldisc.path).await {
+                export_for(uc, &fulldisc.path, &shrunk.img);
+            }
+            last = Some(fulldisc);
         }
 
-        // Set that badboy as your wallpaper.
-        let mut fulldisc: FullDisc = assemble_full_disc(hwdt.into(), &uc, &cli, rx)
-            .await
-            .unwrap();
-
-        if cli.resize {
-            fulldisc.resize_this(5120, 5120).unwrap();
+        if let Some(fulldisc) = last {
+            that(fulldisc.path)?;
         }
+    };
 
-        let mut fd = File::open(fulldisc.path).unwrap();
-
-        let mut result = vec![];
-        fd.read_to_end(&mut result).unwrap();
-
-        let expected = Path::new("./test_data/fulldisc-2022-09-21 00_10.png");
-        let mut test_fd = File::open(expected).unwrap();
-
-        let mut expected = vec![];
-        test_fd.read_to_end(&mut expected).unwrap();
-
-        assert_eq!(expected, result);
-    }
+    Ok(())
 }
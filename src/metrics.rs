@@ -0,0 +1,153 @@
+//! Prometheus metrics, for when rustwari's running as a service. Served on the `metrics:` address
+//! from the config.yml, and at `/metrics` in `serve` mode.
+
+use anyhow::Result;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::info;
+use prometheus::{
+    exponential_buckets, Encoder, Gauge, Histogram, HistogramOpts, IntCounter, Registry,
+    TextEncoder,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+
+/// Everything we keep count of.
+pub struct Metrics {
+    registry: Registry,
+    pub tiles_fetched: IntCounter,
+    pub tiles_failed: IntCounter,
+    pub tiles_retried: IntCounter,
+    /// Missing tiles filled in from an earlier frame.
+    pub tiles_substituted: IntCounter,
+    pub bytes_downloaded: IntCounter,
+    /// Per successful tile request.
+    pub tile_latency: Histogram,
+    /// From the first tile arriving to the image being on disk.
+    pub assembly_duration: Histogram,
+    /// Time spent decoding tiles and encoding the disc, within the above.
+    pub encode_duration: Histogram,
+    /// Unix time of the last wallpaper successfully set.
+    pub last_success: Gauge,
+    pub set_failures: IntCounter,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("rustwari".into()), None).expect("the prefix is valid");
+
+        let counter = |name: &str, help: &str| {
+            let c = IntCounter::new(name, help).expect("valid metric");
+            registry
+                .register(Box::new(c.clone()))
+                .expect("unique metric");
+            c
+        };
+        let histogram = |name: &str, help: &str, buckets: Vec<f64>| {
+            let h = Histogram::with_opts(HistogramOpts::new(name, help).buckets(buckets))
+                .expect("valid metric");
+            registry
+                .register(Box::new(h.clone()))
+                .expect("unique metric");
+            h
+        };
+        let seconds = |start| exponential_buckets(start, 2.0, 12).expect("valid buckets");
+
+        let m = Self {
+            tiles_fetched: counter("tiles_fetched_total", "Tiles successfully downloaded."),
+            tiles_failed: counter("tiles_failed_total", "Failed tile requests."),
+            tiles_retried: counter("tiles_retried_total", "Tile requests that were retried."),
+            tiles_substituted: counter(
+                "tiles_substituted_total",
+                "Missing tiles filled in from an earlier frame.",
+            ),
+            bytes_downloaded: counter("bytes_downloaded_total", "Bytes of tiles downloaded."),
+            tile_latency: histogram(
+                "tile_latency_seconds",
+                "How long each tile took to download.",
+                seconds(0.01),
+            ),
+            assembly_duration: histogram(
+                "assembly_duration_seconds",
+                "How long assembling a disc took.",
+                seconds(0.1),
+            ),
+            encode_duration: histogram(
+                "encode_duration_seconds",
+                "Time spent decoding tiles and encoding a disc.",
+                seconds(0.1),
+            ),
+            last_success: Gauge::new(
+                "last_success_timestamp_seconds",
+                "When the wallpaper was last set.",
+            )
+            .expect("valid metric"),
+            set_failures: counter("set_failures_total", "Failures to set the wallpaper."),
+            registry,
+        };
+        m.registry
+            .register(Box::new(m.last_success.clone()))
+            .expect("unique metric");
+        m
+    }
+
+    /// Everything, in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut out)
+            .expect("metrics encode");
+        String::from_utf8(out).expect("metrics are utf8")
+    }
+}
+
+/// The process' metrics.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+/// The `/metrics` response.
+pub fn response() -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, TextEncoder::new().format_type())
+        .body(Body::from(metrics().render()))
+        .expect("valid response")
+}
+
+/// Serve `/metrics` on `addr` until the process exits.
+pub async fn serve_metrics(addr: SocketAddr) -> Result<()> {
+    let make_svc = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+            Ok::<_, Infallible>(match (req.method(), req.uri().path()) {
+                (&Method::GET, "/metrics") => response(),
+                _ => Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::empty())
+                    .expect("valid response"),
+            })
+        }))
+    });
+
+    info!("Metrics on http://{addr}/metrics");
+    Server::try_bind(&addr)?.serve(make_svc).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prefixed() {
+        metrics().tiles_fetched.inc();
+        metrics().tile_latency.observe(0.2);
+        let text = metrics().render();
+        assert!(text.contains("# TYPE rustwari_tiles_fetched_total counter"));
+        assert!(text.contains("rustwari_tile_latency_seconds_bucket"));
+        assert!(text.contains("rustwari_last_success_timestamp_seconds"));
+    }
+}
@@ -0,0 +1,303 @@
+//! Copies of the disc for places other than the desktop, a lock screen, login greeter, screensaver
+//! etc, each with its own size, format and composition, see `extra_outputs:` in the config.yml
+
+use crate::cvutils::{get_dims, thumbnail, write_raw, OutputFormat, OutputSettings, RESIZE_TARGET};
+use crate::fileutils::AtomicFile;
+use crate::palette::SAMPLE_PX;
+use crate::user_config::Config;
+
+use anyhow::{anyhow, Result};
+use image::imageops::FilterType;
+use image::{DynamicImage, RgbImage, Rgba, RgbaImage};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Outputs without a width or height are no bigger than this, rather than all 121MP of the disc.
+const MAX_UNSIZED: u32 = RESIZE_TARGET;
+
+/// How the disc is placed on an output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compose {
+    /// All of the disc, centred, with `background` around it.
+    #[default]
+    Fit,
+    /// Cropped (centred) so there's no background, on all but a square output that's the poles
+    /// or the sides of the disc gone.
+    Fill,
+}
+
+/// An image written after every wallpaper change.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExtraOutput {
+    pub path: String,
+    /// In px, unset is the same as the other, both unset is the size of the disc (up to 5120).
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+    /// Worked out from `path`'s extension unless set.
+    #[serde(default)]
+    pub format: Option<OutputFormat>,
+    /// 1-100, only used by jpeg and avif.
+    #[serde(default = "default_quality")]
+    pub quality: u8,
+    #[serde(default)]
+    pub compose: Compose,
+    /// How big the disc is, 1.0 is as big as `compose` allows.
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+    /// What's around the disc, `#rrggbb`.
+    #[serde(default = "default_background")]
+    pub background: String,
+}
+
+fn default_quality() -> u8 {
+    90
+}
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+fn default_background() -> String {
+    "#000000".into()
+}
+
+fn parse_hex(s: &str) -> Result<Rgba<u8>> {
+    let hex = s.trim_start_matches('#');
+    let bad = || anyhow!("{s} isn't a colour, try #rrggbb");
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(bad());
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| bad());
+    Ok(Rgba([channel(0)?, channel(2)?, channel(4)?, 255]))
+}
+
+impl ExtraOutput {
+    /// The width and height of this output, for a `disc` sized disc.
+    pub fn dims(&self, disc: u32) -> (u32, u32) {
+        let unsized_px = disc.min(MAX_UNSIZED);
+        let w = self.width.or(self.height).unwrap_or(unsized_px);
+        let h = self.height.or(self.width).unwrap_or(unsized_px);
+        (w, h)
+    }
+
+    /// How big the disc's drawn on this output, for a `disc` sized disc.
+    pub fn disc_px(&self, disc: u32) -> u32 {
+        let (w, h) = self.dims(disc);
+        let side = match self.compose {
+            Compose::Fit => w.min(h),
+            Compose::Fill => w.max(h),
+        };
+        ((side as f32 * self.scale).round() as u32).max(1)
+    }
+
+    /// How this output is encoded, `fallback` is the `output:` format for unknown extensions.
+    pub fn settings(&self, fallback: OutputFormat) -> OutputSettings {
+        let from_ext = Path::new(&self.path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .and_then(|e| match e.to_ascii_lowercase().as_str() {
+                "png" => Some(OutputFormat::Png),
+                "jpg" | "jpeg" => Some(OutputFormat::Jpeg),
+                "webp" => Some(OutputFormat::Webp),
+                "avif" => Some(OutputFormat::Avif),
+                _ => None,
+            });
+        OutputSettings {
+            format: self.format.or(from_ext).unwrap_or(fallback),
+            quality: self.quality,
+            rgb: true,
+            ..Default::default()
+        }
+    }
+
+    /// `disc` (shrunk from one `side` px across), composed for this output.
+    pub fn compose(&self, disc: &RgbImage, side: u32) -> Result<RgbaImage> {
+        let (w, h) = self.dims(side);
+        let d = self.disc_px(side);
+
+        let mut canvas = RgbaImage::from_pixel(w, h, parse_hex(&self.background)?);
+        let resized = image::imageops::resize(disc, d, d, FilterType::Lanczos3);
+        let resized = DynamicImage::ImageRgb8(resized).to_rgba8();
+        let (x, y) = ((w as i64 - d as i64) / 2, (h as i64 - d as i64) / 2);
+        image::imageops::overlay(&mut canvas, &resized, x, y);
+        Ok(canvas)
+    }
+
+    /// Compose `disc`, then write it to `path`.
+    pub fn write(&self, disc: &RgbImage, side: u32, fallback: OutputFormat) -> Result<PathBuf> {
+        let img = DynamicImage::ImageRgba8(self.compose(disc, side)?);
+        let out = self.settings(fallback);
+
+        let (tmp, fd) = AtomicFile::create(&self.path)?;
+        let mut fd = BufWriter::new(fd);
+        write_raw(
+            &mut fd,
+            &out.pixels_of(&img),
+            img.width(),
+            img.height(),
+            &out,
+        )?;
+        fd.flush()?;
+        drop(fd);
+        tmp.commit()?;
+        debug!("Wrote {}", self.path);
+        Ok(self.path.clone().into())
+    }
+}
+
+/// A disc, decoded once (and only as big as needed) for the palette and extra outputs to share.
+#[derive(Debug, Clone)]
+pub struct ShrunkDisc {
+    pub img: RgbImage,
+    /// How big the disc it was shrunk from is.
+    pub side: u32,
+}
+
+/// How big a [`ShrunkDisc`] has to be for whichever of the palette and extra outputs are
+/// configured, `None` if neither are.
+pub fn shrunk_px(uc: &Config, side: u32) -> Option<u32> {
+    let outputs = uc.extra_outputs.iter().map(|o| o.disc_px(side));
+    let palette = uc.palette.enabled.then_some(SAMPLE_PX);
+    outputs.chain(palette).max().map(|px| px.min(side))
+}
+
+/// The disc at `p`, shrunk for the palette and extra outputs (off the async threads, it's slow),
+/// `None` if there's nothing to use it for or it can't be read (which is only worth a warning).
+pub async fn shrunk_disc(uc: &Config, p: &Path) -> Option<ShrunkDisc> {
+    let (side, _) = match get_dims(p) {
+        Ok(dims) => dims,
+        Err(e) => {
+            warn!("Unable to read {}: {e}", p.display());
+            return None;
+        }
+    };
+    let px = shrunk_px(uc, side)?;
+    let path = p.to_path_buf();
+    let img = tokio::task::spawn_blocking(move || thumbnail(path, px)).await;
+    match img.map_err(anyhow::Error::from).and_then(|img| img) {
+        Ok(img) => Some(ShrunkDisc { img, side }),
+        Err(e) => {
+            warn!("Unable to open {}: {e}", p.display());
+            None
+        }
+    }
+}
+
+/// Write each of the configured `extra_outputs` from `disc` (off the async threads), returning
+/// those that were, a failure's only worth a warning.
+pub async fn write_outputs(uc: &Config, disc: ShrunkDisc) -> Vec<PathBuf> {
+    if uc.extra_outputs.is_empty() {
+        return vec![];
+    }
+    let (outputs, format) = (uc.extra_outputs.clone(), uc.output.format);
+    let written = tokio::task::spawn_blocking(move || {
+        outputs
+            .iter()
+            .filter_map(|o| match o.write(&disc.img, disc.side, format) {
+                Ok(p) => Some(p),
+                Err(e) => {
+                    warn!("Unable to write {}: {e}", o.path);
+                    None
+                }
+            })
+            .collect()
+    })
+    .await;
+    written.unwrap_or_else(|e| {
+        warn!("Unable to write the extra outputs: {e}");
+        vec![]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+
+    fn output(yaml: &str) -> ExtraOutput {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn composition() {
+        let disc = RgbImage::from_pixel(100, 100, Rgb([255, 0, 0]));
+
+        let fit = output("{path: lock.png, width: 160, height: 90, background: '#102030'}");
+        let img = fit.compose(&disc, 100).unwrap();
+        assert_eq!(img.dimensions(), (160, 90));
+        assert_eq!(img.get_pixel(80, 45), &RED);
+        assert_eq!(img.get_pixel(10, 45), &Rgba([16, 32, 48, 255]));
+        assert_eq!(img.get_pixel(34, 45), &Rgba([16, 32, 48, 255]));
+        assert_eq!(img.get_pixel(36, 45), &RED);
+
+        let small = output("{path: lock.png, width: 100, scale: 0.5}");
+        let img = small.compose(&disc, 100).unwrap();
+        assert_eq!(img.dimensions(), (100, 100));
+        assert_eq!(img.get_pixel(20, 50), &Rgba([0, 0, 0, 255]));
+        assert_eq!(img.get_pixel(50, 50), &RED);
+
+        let fill = output("{path: greeter.jpg, width: 160, height: 90, compose: fill}");
+        let img = fill.compose(&disc, 100).unwrap();
+        assert_eq!(img.get_pixel(0, 0), &RED);
+        assert_eq!(img.get_pixel(159, 89), &RED);
+
+        assert!(output("{path: x.png, background: blue}")
+            .compose(&disc, 100)
+            .is_err());
+    }
+
+    #[test]
+    fn formats_follow_the_extension() {
+        let o = |p: &str| output(&format!("{{path: '{p}'}}")).settings(OutputFormat::Webp);
+        assert_eq!(o("lock.JPG").format, OutputFormat::Jpeg);
+        assert_eq!(o("lock.png").format, OutputFormat::Png);
+        assert_eq!(o("lock").format, OutputFormat::Webp);
+        assert_eq!(
+            output("{path: lock.png, format: jpeg}")
+                .settings(OutputFormat::Png)
+                .format,
+            OutputFormat::Jpeg
+        );
+
+        let p = std::env::temp_dir().join("rustwari_formats_follow_the_extension/lock.jpg");
+        let disc = RgbImage::from_pixel(64, 64, Rgb([255, 0, 0]));
+        output(&format!("{{path: '{}', width: 32}}", p.display()))
+            .write(&disc, 64, OutputFormat::Png)
+            .unwrap();
+        assert_eq!(image::image_dimensions(&p).unwrap(), (32, 32));
+        assert_eq!(
+            image::ImageFormat::from_path(&p).unwrap(),
+            image::ImageFormat::Jpeg
+        );
+        std::fs::remove_dir_all(p.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn discs_are_only_as_big_as_needed() {
+        assert_eq!(output("{path: x.png}").dims(11000), (5120, 5120));
+        assert_eq!(output("{path: x.png}").dims(1000), (1000, 1000));
+
+        let uc = |yaml: &str| -> Config {
+            serde_yaml::from_str(&format!("completed: c\nbackup: b\n{yaml}")).unwrap()
+        };
+        let outputs = "
+extra_outputs:
+  - {path: lock.png, width: 2560, height: 1440, scale: 0.5}
+  - {path: greeter.png, width: 1920, height: 1080, compose: fill}
+";
+        assert_eq!(shrunk_px(&uc(outputs), 11000), Some(1920));
+        assert_eq!(shrunk_px(&uc(outputs), 1000), Some(1000));
+        assert_eq!(
+            shrunk_px(&uc("palette: {enabled: true}"), 11000),
+            Some(SAMPLE_PX)
+        );
+        assert_eq!(shrunk_px(&uc(""), 11000), None);
+    }
+}
@@ -0,0 +1,394 @@
+//! The colours of a disc, as pywal JSON, Xresources, CSS variables and a kitty colour scheme written
+//! next to it so your terminal can change colour along with the wallpaper, see the `palette:`
+//! section of the config.yml
+
+use crate::fileutils::AtomicFile;
+use crate::user_config::Config;
+
+use anyhow::Result;
+use image::RgbImage;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Anything with no channel brighter than this is space (or night), and ignored.
+const BLACK_CUTOFF: u8 = 24;
+/// Discs are shrunk to (at most) this wide before they're looked at, there's plenty of pixels left.
+pub const SAMPLE_PX: u32 = 256;
+/// How many colours are pulled out of a disc, the 16 in a terminal scheme are made from these.
+const DOMINANT: usize = 8;
+/// Rounds of k-means.
+const ITERATIONS: usize = 10;
+
+pub type Colour = [u8; 3];
+
+/// How the dominant colours are found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Method {
+    /// Median-cut, refined with k-means.
+    #[default]
+    Kmeans,
+    /// Just median-cut, quicker, a little muddier.
+    MedianCut,
+}
+
+/// The files written next to each image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaletteFormat {
+    /// `<image>.colors.json`, as pywal writes to `~/.cache/wal/colors.json`.
+    Pywal,
+    /// `<image>.Xresources`
+    Xresources,
+    /// `<image>.colors.css`, `--background`, `--color0` etc on `:root`.
+    Css,
+    /// `<image>.kitty.conf`, a kitty colour scheme.
+    Kitty,
+}
+
+impl PaletteFormat {
+    fn suffix(&self) -> &'static str {
+        match self {
+            Self::Pywal => "colors.json",
+            Self::Xresources => "Xresources",
+            Self::Css => "colors.css",
+            Self::Kitty => "kitty.conf",
+        }
+    }
+}
+
+/// Lives under `palette:` in the config.yml, off unless enabled.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PaletteSettings {
+    pub enabled: bool,
+    pub method: Method,
+    pub formats: Vec<PaletteFormat>,
+}
+
+impl Default for PaletteSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            method: Method::Kmeans,
+            formats: vec![
+                PaletteFormat::Pywal,
+                PaletteFormat::Xresources,
+                PaletteFormat::Css,
+                PaletteFormat::Kitty,
+            ],
+        }
+    }
+}
+
+/// Where the `format` file for the image at `p` goes.
+pub fn sidecar(p: &Path, format: PaletteFormat) -> PathBuf {
+    let stem = p.file_stem().unwrap_or_default().to_string_lossy();
+    p.with_file_name(format!("{stem}.{}", format.suffix()))
+}
+
+/// Every palette file the image at `p` may have, so they can go when it does.
+pub fn sidecars(p: &Path) -> Vec<PathBuf> {
+    [
+        PaletteFormat::Pywal,
+        PaletteFormat::Xresources,
+        PaletteFormat::Css,
+        PaletteFormat::Kitty,
+    ]
+    .into_iter()
+    .map(|f| sidecar(p, f))
+    .collect()
+}
+
+fn luminance(c: &Colour) -> f32 {
+    0.2126 * c[0] as f32 + 0.7152 * c[1] as f32 + 0.0722 * c[2] as f32
+}
+
+/// `c` moved `t` (0.0 - 1.0) of the way towards `to`.
+fn mix(c: Colour, to: Colour, t: f32) -> Colour {
+    [0, 1, 2].map(|i| (c[i] as f32 + (to[i] as f32 - c[i] as f32) * t).round() as u8)
+}
+
+fn distance(a: &Colour, b: &Colour) -> u32 {
+    (0..3)
+        .map(|i| (a[i] as i32 - b[i] as i32).pow(2) as u32)
+        .sum()
+}
+
+fn mean(pixels: &[Colour]) -> Colour {
+    let mut sum = [0u64; 3];
+    for p in pixels {
+        for i in 0..3 {
+            sum[i] += p[i] as u64;
+        }
+    }
+    sum.map(|s| (s / pixels.len().max(1) as u64) as u8)
+}
+
+/// The pixels of `img` that aren't space.
+fn lit_pixels(img: &RgbImage) -> Vec<Colour> {
+    let img = match img.width() > SAMPLE_PX {
+        true => image::imageops::thumbnail(img, SAMPLE_PX, SAMPLE_PX * img.height() / img.width()),
+        false => img.clone(),
+    };
+    img.pixels()
+        .map(|p| p.0)
+        .filter(|p| p.iter().any(|c| *c > BLACK_CUTOFF))
+        .collect()
+}
+
+/// Split the box with the widest spread of any channel at its median until there are `k`.
+fn median_cut(pixels: &[Colour], k: usize) -> Vec<Colour> {
+    let mut boxes = vec![pixels.to_vec()];
+    while boxes.len() < k {
+        let spread = |b: &Vec<Colour>, i: usize| {
+            let (lo, hi) = b
+                .iter()
+                .fold((u8::MAX, 0), |(lo, hi), p| (lo.min(p[i]), hi.max(p[i])));
+            hi.saturating_sub(lo)
+        };
+        let Some((widest, channel)) = boxes
+            .iter()
+            .enumerate()
+            .flat_map(|(n, b)| (0..3).map(move |i| (n, i, spread(b, i))))
+            .filter(|(_, _, s)| *s > 0)
+            .max_by_key(|(_, _, s)| *s)
+            .map(|(n, i, _)| (n, i))
+        else {
+            break;
+        };
+        let mut b = boxes.swap_remove(widest);
+        b.sort_unstable_by_key(|p| p[channel]);
+        let upper = b.split_off(b.len() / 2);
+        boxes.extend([b, upper]);
+    }
+    boxes.iter().map(|b| mean(b)).collect()
+}
+
+/// Lloyd's k-means, starting from `centres`, empty clusters are dropped.
+fn kmeans(pixels: &[Colour], mut centres: Vec<Colour>) -> Vec<Colour> {
+    for _ in 0..ITERATIONS {
+        let mut clusters = vec![vec![]; centres.len()];
+        for p in pixels {
+            let nearest = (0..centres.len())
+                .min_by_key(|&n| distance(p, &centres[n]))
+                .unwrap_or_default();
+            clusters[nearest].push(*p);
+        }
+        let moved = clusters
+            .iter()
+            .filter(|c| !c.is_empty())
+            .map(|c| mean(c))
+            .collect::<Vec<_>>();
+        if moved == centres {
+            break;
+        }
+        centres = moved;
+    }
+    centres
+}
+
+/// The `DOMINANT` (or fewer, if there's not that much going on) colours of `img`, ignoring space.
+pub fn dominant(img: &RgbImage, method: Method) -> Vec<Colour> {
+    let pixels = lit_pixels(img);
+    if pixels.is_empty() {
+        return vec![];
+    }
+    let mut colours = median_cut(&pixels, DOMINANT);
+    if method == Method::Kmeans {
+        colours = kmeans(&pixels, colours);
+    }
+    colours.sort_unstable();
+    colours.dedup();
+    colours
+}
+
+/// A terminal's worth of colours.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    pub background: Colour,
+    pub foreground: Colour,
+    pub colors: [Colour; 16],
+}
+
+impl Palette {
+    /// Much like pywal does it, the darkest colour (darker still) is the background, the lightest
+    /// (lighter still) the foreground, 1-6 are the rest and 9-14 brighter versions of them.
+    pub fn from_dominant(mut dominant: Vec<Colour>) -> Self {
+        const BLACK: Colour = [0, 0, 0];
+        const WHITE: Colour = [255, 255, 255];
+        if dominant.is_empty() {
+            dominant = vec![[128, 128, 128]];
+        }
+        dominant.sort_by(|a, b| luminance(a).total_cmp(&luminance(b)));
+
+        let background = mix(dominant[0], BLACK, 0.8);
+        let foreground = mix(dominant[dominant.len() - 1], WHITE, 0.75);
+        let accents = match dominant.len() {
+            1 => &dominant[..],
+            _ => &dominant[1..],
+        };
+
+        let mut colors = [background; 16];
+        for i in 0..6 {
+            let c = accents[i % accents.len()];
+            colors[1 + i] = c;
+            colors[9 + i] = mix(c, WHITE, 0.3);
+        }
+        colors[7] = foreground;
+        colors[8] = mix(background, WHITE, 0.25);
+        colors[15] = foreground;
+
+        Self {
+            background,
+            foreground,
+            colors,
+        }
+    }
+
+    pub fn from_image(img: &RgbImage, method: Method) -> Self {
+        Self::from_dominant(dominant(img, method))
+    }
+
+    /// The file for `format`, `wallpaper` is what it was made from.
+    pub fn render(&self, format: PaletteFormat, wallpaper: &Path) -> Result<String> {
+        let hex = |c: &Colour| format!("#{:02x}{:02x}{:02x}", c[0], c[1], c[2]);
+        let (bg, fg) = (hex(&self.background), hex(&self.foreground));
+        let numbered = self.colors.iter().enumerate().map(|(i, c)| (i, hex(c)));
+
+        Ok(match format {
+            PaletteFormat::Pywal => {
+                let colors = numbered
+                    .map(|(i, c)| (format!("color{i}"), c.into()))
+                    .collect::<serde_json::Map<_, _>>();
+                serde_json::to_string_pretty(&serde_json::json!({
+                    "wallpaper": wallpaper.display().to_string(),
+                    "alpha": "100",
+                    "special": {"background": bg, "foreground": fg, "cursor": fg},
+                    "colors": colors,
+                }))?
+            }
+            PaletteFormat::Xresources => {
+                let mut out = format!(
+                    "! rustwari, from {}\n*.foreground: {fg}\n*.background: {bg}\n*.cursorColor: {fg}\n",
+                    wallpaper.display()
+                );
+                numbered.for_each(|(i, c)| out.push_str(&format!("*.color{i}: {c}\n")));
+                out
+            }
+            PaletteFormat::Css => {
+                let mut out = format!(
+                    "/* rustwari, from {} */\n:root {{\n  --background: {bg};\n  --foreground: {fg};\n  --cursor: {fg};\n",
+                    wallpaper.display()
+                );
+                numbered.for_each(|(i, c)| out.push_str(&format!("  --color{i}: {c};\n")));
+                out.push_str("}\n");
+                out
+            }
+            PaletteFormat::Kitty => {
+                let mut out = format!(
+                    "# rustwari, from {}\nforeground {fg}\nbackground {bg}\ncursor {fg}\n",
+                    wallpaper.display()
+                );
+                numbered.for_each(|(i, c)| out.push_str(&format!("color{i} {c}\n")));
+                out
+            }
+        })
+    }
+}
+
+/// Work out the palette of `disc` (the image at `p`, shrunk, see
+/// [`crate::outputs::shrunk_disc`]), and write it next to `p` in each of `settings.formats`.
+pub fn write_palette(
+    p: &Path,
+    disc: &RgbImage,
+    settings: &PaletteSettings,
+) -> Result<Vec<PathBuf>> {
+    let palette = Palette::from_image(disc, settings.method);
+    let mut written = vec![];
+    for format in settings.formats.iter() {
+        let dest = sidecar(p, *format);
+        let (tmp, mut fd) = AtomicFile::create(&dest)?;
+        fd.write_all(palette.render(*format, p)?.as_bytes())?;
+        drop(fd);
+        tmp.commit()?;
+        debug!("Wrote {}", dest.display());
+        written.push(dest);
+    }
+    Ok(written)
+}
+
+/// [`write_palette`], if it's enabled, a failure's only worth a warning.
+pub fn export_for(uc: &Config, p: &Path, disc: &RgbImage) {
+    if !uc.palette.enabled {
+        return;
+    }
+    if let Err(e) = write_palette(p, disc, &uc.palette) {
+        warn!("Unable to write the palette for {}: {e}", p.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    const BLUE: Colour = [20, 60, 200];
+    const GREEN: Colour = [40, 160, 60];
+
+    /// Mostly space, with a blue half and a green half in the middle.
+    fn disc() -> RgbImage {
+        RgbImage::from_fn(64, 64, |x, y| match (x, y) {
+            (16..=31, 16..=47) => Rgb(BLUE),
+            (32..=47, 16..=47) => Rgb(GREEN),
+            _ => Rgb([3, 2, 4]),
+        })
+    }
+
+    #[test]
+    fn space_is_ignored() {
+        for method in [Method::Kmeans, Method::MedianCut] {
+            let colours = dominant(&disc(), method);
+            assert_eq!(colours.len(), 2, "{method:?}");
+            assert!(colours.contains(&BLUE) && colours.contains(&GREEN));
+        }
+        assert!(dominant(&RgbImage::new(8, 8), Method::Kmeans).is_empty());
+
+        let palette = Palette::from_image(&disc(), Method::Kmeans);
+        // Blue's the darker, so it's the background (darker still), leaving green for the rest.
+        assert_eq!(palette.background, [4, 12, 40]);
+        assert_eq!(palette.colors[0], palette.background);
+        assert_eq!(palette.colors[1], GREEN);
+        assert_eq!(palette.colors[6], GREEN);
+        assert!(luminance(&palette.foreground) > luminance(&GREEN));
+    }
+
+    #[test]
+    fn exports() {
+        let palette = Palette::from_dominant(vec![BLUE, GREEN]);
+        let p = Path::new("/tmp/fulldisc-2022-09-21 00_10.png");
+
+        let wal: serde_json::Value =
+            serde_json::from_str(&palette.render(PaletteFormat::Pywal, p).unwrap()).unwrap();
+        assert_eq!(wal["colors"]["color1"], "#28a03c");
+        assert_eq!(wal["special"]["background"], "#040c28");
+
+        let xr = palette.render(PaletteFormat::Xresources, p).unwrap();
+        assert!(xr.contains("*.background: #040c28\n"));
+        assert!(palette
+            .render(PaletteFormat::Css, p)
+            .unwrap()
+            .contains("  --color15: "));
+        assert!(palette
+            .render(PaletteFormat::Kitty, p)
+            .unwrap()
+            .contains("\ncolor1 #28a03c\n"));
+
+        assert_eq!(
+            sidecar(p, PaletteFormat::Pywal),
+            Path::new("/tmp/fulldisc-2022-09-21 00_10.colors.json")
+        );
+    }
+}
@@ -0,0 +1,207 @@
+//! The other half of `rustwari serve`, fetching the finished disc from another rustwari instance
+//! rather than 400 tiles from NICT.
+
+use crate::archive::{checksum, Archive, ArchiveEntry};
+use crate::cvutils::{OutputFormat, OutputSettings};
+use crate::filenames::FrameName;
+use crate::fileutils::AtomicFile;
+use crate::himawaridt::{HimawariDatetime, LEVEL};
+use crate::progress::{self, ProgressEvent};
+use crate::server::LatestMeta;
+use crate::user_config::Config;
+use crate::wallpaperutils::FullDisc;
+
+use anyhow::{bail, Result};
+use log::{debug, warn};
+use reqwest::Client;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::Path;
+use url::Url;
+
+/// `source: peer` in the config.yml selects a peer, set `peer:` to its url.
+pub const PEER: &str = "peer";
+
+/// Another rustwari instance, running `serve`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub base_url: Url,
+}
+
+impl Peer {
+    pub fn new(base_url: &str) -> Result<Self> {
+        let mut base_url = Url::parse(base_url.trim())?;
+        if !["http", "https"].contains(&base_url.scheme()) {
+            bail!("Unsupported scheme for peer: {}", base_url.scheme());
+        }
+        // Without the trailing / joining would replace the last segment.
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+        Ok(Self { base_url })
+    }
+
+    /// The peer from the user's config.yml, if `source: peer` is set.
+    pub fn from_config(uc: &Config) -> Result<Option<Self>> {
+        if uc.source != PEER {
+            return Ok(None);
+        }
+        match &uc.peer {
+            Some(url) => Ok(Some(Self::new(url)?)),
+            None => bail!("source: peer needs `peer:` set to the url of a rustwari serve instance"),
+        }
+    }
+
+    /// What the peer is currently serving.
+    pub async fn latest_meta(&self, client: &Client) -> Result<LatestMeta> {
+        let body = client
+            .get(self.base_url.join("latest.json")?)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Download the peer's latest disc into `completed`, it's skipped if we already have it.
+    pub async fn fetch_latest(&self, client: &Client, uc: &Config) -> Result<FullDisc> {
+        let meta = self.latest_meta(client).await?;
+        let hwdt: HimawariDatetime = meta.timestamp.parse()?;
+        let name = FrameName {
+            hwdt,
+            source: meta.source.clone(),
+            product: meta.product.clone(),
+            level: LEVEL.into(),
+            ext: "jpg".into(),
+        };
+        let p = Path::new(&uc.completed).join(uc.filename.render(&name));
+        let out = OutputSettings {
+            format: OutputFormat::Jpeg,
+            rgb: true,
+            ..Default::default()
+        };
+
+        if p.is_file() && checksum(&p)? == meta.sha256 {
+            debug!("Already have {} from {}", meta.timestamp, self.base_url);
+            return FullDisc::new(&p, out);
+        }
+
+        let bytes = client
+            .get(self.base_url.join("latest.jpg")?)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+
+        // The peer may have moved on between the two requests, so check we got what was described.
+        let actual = format!("{:x}", Sha256::digest(&bytes));
+        if actual != meta.sha256 {
+            bail!(
+                "{} sent {actual} but described {}",
+                self.base_url,
+                meta.sha256
+            );
+        }
+
+        let (tmp, mut fd) = AtomicFile::create(&p)?;
+        fd.write_all(&bytes)?;
+        drop(fd);
+        tmp.commit()?;
+        progress::emit(ProgressEvent::Saved { path: p.clone() });
+
+        let archive = Archive::from_config(uc);
+        match ArchiveEntry::from_file(&p, &archive.source, &archive.template) {
+            Ok(mut entry) => {
+                entry.source = meta.source;
+                entry.product = meta.product;
+                archive.record(&entry)?;
+            }
+            Err(e) => warn!("Unable to archive {}: {e}", p.display()),
+        }
+
+        FullDisc::new(&p, out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peer_urls() {
+        assert_eq!(
+            Peer::new("http://rustwari.corp:8080/himawari")
+                .unwrap()
+                .base_url
+                .join("latest.json")
+                .unwrap()
+                .as_str(),
+            "http://rustwari.corp:8080/himawari/latest.json"
+        );
+        assert!(Peer::new("file:///tmp").is_err());
+        assert!(Peer::new("not a url").is_err());
+    }
+
+    #[tokio::test]
+    async fn fetches_from_a_server() {
+        use crate::server::{handle, Latest, SharedFrames};
+        use hyper::service::{make_service_fn, service_fn};
+        use std::sync::Arc;
+        use tokio::sync::RwLock;
+
+        let dir = std::env::temp_dir().join("rustwari_fetches_from_a_server");
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let uc: Config = serde_yaml::from_str(&format!(
+            "completed: {0}\nbackup: {0}\nsource: peer\npeer: http://localhost",
+            dir.display()
+        ))
+        .unwrap();
+
+        let mut jpeg = std::io::Cursor::new(vec![]);
+        image::DynamicImage::new_rgb8(8, 8)
+            .write_to(&mut jpeg, image::ImageOutputFormat::Jpeg(90))
+            .unwrap();
+        let jpeg = bytes::Bytes::from(jpeg.into_inner());
+        let latest = Arc::new(RwLock::new(Some(Latest {
+            meta: LatestMeta {
+                timestamp: "2022-09-21 00:10".into(),
+                source: "himawari8".into(),
+                product: "D531106".into(),
+                width: 8,
+                height: 8,
+                size: jpeg.len() as u64,
+                sha256: format!("{:x}", Sha256::digest(&jpeg)),
+                frame: "/frames/2022-09-21T00:10".into(),
+            },
+            jpeg,
+        })));
+
+        let frames = SharedFrames::default();
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(
+            move |_| {
+                let latest = latest.clone();
+                let frames = frames.clone();
+                async move {
+                    Ok::<_, std::convert::Infallible>(service_fn(move |req| {
+                        handle(req, latest.clone(), frames.clone())
+                    }))
+                }
+            },
+        ));
+        let peer = Peer::new(&format!("http://{}", server.local_addr())).unwrap();
+        tokio::spawn(server);
+
+        let fulldisc = peer.fetch_latest(&Client::new(), &uc).await.unwrap();
+        assert!(fulldisc.path.ends_with("fulldisc-2022-09-21 00_10.jpg"));
+        assert_eq!((fulldisc.width, fulldisc.height), (8, 8));
+        assert_eq!(
+            Archive::from_config(&uc).find("2022-09-21").unwrap().len(),
+            1
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
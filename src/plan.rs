@@ -0,0 +1,187 @@
+//! What a run would do, for `--dry-run`, worked out without fetching, writing or setting anything.
+
+use crate::cli::Cli;
+use crate::cvutils::{tile_px_for, COLMAX, ROWMAX};
+use crate::filenames::frame_path;
+use crate::himawaridt::HimawariDatetime;
+use crate::hooks::Stage;
+use crate::palette::sidecar;
+use crate::peer::Peer;
+use crate::retention::prune;
+use crate::sources::TileSource;
+use crate::user_config::Config;
+use crate::wallpaperutils::WallpaperBackend;
+
+use anyhow::Result;
+
+/// One line per thing that'd happen (indented beneath whatever it's part of), `frames` are what
+/// would be fetched, `set` being a normal run (which sets the last of them as the wallpaper, runs
+/// the hooks etc) rather than a --oneshot (which only opens it).
+pub fn plan(
+    uc: &Config,
+    cli: &Cli,
+    frames: &[HimawariDatetime],
+    backend: &dyn WallpaperBackend,
+    set: bool,
+) -> Result<Vec<String>> {
+    let source = TileSource::from_config(uc)?;
+    let peer = match set {
+        true => Peer::from_config(uc)?,
+        false => None,
+    };
+    let side = tile_px_for(cli) * COLMAX;
+    let mut lines = vec![];
+
+    if set {
+        for hook in uc.hooks.for_stage(Stage::PreFetch) {
+            lines.push(hook_line(Stage::PreFetch, &hook.command, hook.timeout_secs));
+        }
+    }
+
+    for hwdt in frames {
+        lines.push(format!("frame {hwdt} UTC"));
+        if let Some(peer) = &peer {
+            if *hwdt == HimawariDatetime::closest_to_now() {
+                lines.push(format!(
+                    "  from the peer at {}, the tiles below only if it's unreachable",
+                    peer.base_url
+                ));
+            }
+        }
+        lines.push(format!("  {} tiles from {}", ROWMAX * COLMAX, source.name));
+        for y in 0..ROWMAX {
+            for x in 0..COLMAX {
+                lines.push(format!("    {}", source.tile_url(hwdt, x, y)?));
+            }
+        }
+
+        let p = frame_path(&uc.completed, uc, *hwdt);
+        lines.push(format!(
+            "  save {} ({side}x{side} {})",
+            p.display(),
+            uc.output.extension()
+        ));
+        if uc.palette.enabled {
+            for format in uc.palette.formats.iter() {
+                lines.push(format!("  palette {}", sidecar(&p, *format).display()));
+            }
+        }
+    }
+
+    let Some(last) = frames.last() else {
+        return Ok(lines);
+    };
+    let p = frame_path(&uc.completed, uc, *last);
+    if !set {
+        lines.push(format!("open {}", p.display()));
+        return Ok(lines);
+    }
+
+    for hook in uc.hooks.for_stage(Stage::PostSave) {
+        lines.push(hook_line(Stage::PostSave, &hook.command, hook.timeout_secs));
+    }
+    lines.push(format!(
+        "set with {}: {}",
+        backend.name(),
+        backend.describe(&p)
+    ));
+    for o in uc.extra_outputs.iter() {
+        let (w, h) = o.dims(side);
+        lines.push(format!(
+            "extra output {} ({w}x{h} {}, {:?} at {} on {})",
+            o.path,
+            o.settings(uc.output.format).extension(),
+            o.compose,
+            o.scale,
+            o.background
+        ));
+    }
+    for hook in uc.hooks.for_stage(Stage::PostSet) {
+        lines.push(hook_line(Stage::PostSet, &hook.command, hook.timeout_secs));
+    }
+    if cli.backup {
+        lines.push(format!(
+            "backup {}",
+            frame_path(&uc.backup, uc, *last).display()
+        ));
+    }
+
+    // Only what's there now, the new disc will count towards `keep_last` etc too.
+    match prune(uc, true) {
+        Ok(doomed) => {
+            for c in doomed {
+                lines.push(format!("would remove {}", c.path.display()));
+            }
+        }
+        Err(e) => lines.push(format!("unable to check the retention policies: {e}")),
+    }
+    Ok(lines)
+}
+
+fn hook_line(stage: Stage, command: &str, timeout_secs: u64) -> String {
+    format!("{stage} hook: {command} (up to {timeout_secs}s)")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cvutils::RESIZE_TARGET;
+    use crate::wallpaperutils::System;
+    use clap::Parser;
+
+    #[test]
+    fn plans() {
+        let uc: Config = serde_yaml::from_str(
+            "
+completed: /nonexistent/completed
+backup: /nonexistent/backup
+extra_outputs:
+  - path: /tmp/lock.jpg
+    width: 1920
+hooks:
+  post_set:
+    - command: wal -i \"$RUSTWARI_IMAGE\"
+",
+        )
+        .unwrap();
+        let cli = Cli::parse_from(["rustwari", "--resize", "--backup", "--dry-run"]);
+        let hwdt = "2022-09-21 00:10".parse().unwrap();
+        let lines = plan(&uc, &cli, &[hwdt], &System, true).unwrap();
+
+        assert_eq!(lines[0], "frame 2022-09-21 00:10 UTC");
+        assert_eq!(lines[1], "  400 tiles from himawari8");
+        assert_eq!(
+            lines[2],
+            "    https://himawari8.nict.go.jp/img/D531106/20d/550/2022/09/21/001000_0_0.png"
+        );
+        let rest = &lines[402..];
+        assert_eq!(
+            rest[0],
+            format!(
+                "  save /nonexistent/completed/fulldisc-2022-09-21 00_10.png ({0}x{0} png)",
+                RESIZE_TARGET
+            )
+        );
+        assert!(rest[1].starts_with("set with system: wallpaper::set_from_path("));
+        assert_eq!(
+            rest[2],
+            "extra output /tmp/lock.jpg (1920x1920 jpg, Fit at 1 on #000000)"
+        );
+        assert_eq!(
+            rest[3],
+            "post_set hook: wal -i \"$RUSTWARI_IMAGE\" (up to 30s)"
+        );
+        assert_eq!(
+            rest[4],
+            "backup /nonexistent/backup/fulldisc-2022-09-21 00_10.png"
+        );
+        // There's nothing to prune.
+        assert_eq!(rest.len(), 5);
+
+        let oneshot = plan(&uc, &cli, &[hwdt], &System, false).unwrap();
+        assert_eq!(
+            oneshot.last().unwrap(),
+            "open /nonexistent/completed/fulldisc-2022-09-21 00_10.png"
+        );
+    }
+}
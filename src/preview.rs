@@ -0,0 +1,325 @@
+//! A look at a disc without leaving the terminal (or copying 150MB over SSH), with kitty's graphics
+//! protocol, sixel, iTerm2's inline images, or failing those, coloured half blocks.
+
+use crate::archive::Archive;
+use crate::fileutils::images_in;
+use crate::user_config::Config;
+use crate::when::parse_when;
+
+use anyhow::{bail, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use clap::ValueEnum;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageOutputFormat, RgbImage};
+use std::fmt::Write;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+/// Roughly how many px wide a terminal cell is, for the protocols that draw real pixels.
+const CELL_PX: u32 = 10;
+/// Nobody needs a preview bigger than this, and they'd be slow over SSH.
+const MAX_PX: u32 = 1024;
+/// The kitty graphics protocol wants its payload in chunks no bigger than this.
+const KITTY_CHUNK: usize = 4096;
+
+/// How the preview's drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Protocol {
+    /// Work it out from $TERM etc.
+    Auto,
+    Kitty,
+    Sixel,
+    Iterm,
+    /// Unicode half blocks, two px per cell, works (almost) everywhere.
+    Blocks,
+}
+
+impl Protocol {
+    /// What the terminal (going by `var`) supports, `Blocks` if we can't tell.
+    pub fn detect_from(var: impl Fn(&str) -> Option<String>) -> Self {
+        let term = var("TERM").unwrap_or_default();
+        let program = var("TERM_PROGRAM").unwrap_or_default();
+        // iTerm2 sets LC_TERMINAL so it survives SSH, which passes LC_* along.
+        let lc_terminal = var("LC_TERMINAL").unwrap_or_default();
+
+        if var("KITTY_WINDOW_ID").is_some() || term.contains("kitty") || program == "ghostty" {
+            Self::Kitty
+        } else if program == "iTerm.app" || lc_terminal == "iTerm2" || program == "WezTerm" {
+            Self::Iterm
+        } else if ["sixel", "foot", "mlterm", "yaft", "contour"]
+            .iter()
+            .any(|t| term.contains(t))
+        {
+            Self::Sixel
+        } else {
+            Self::Blocks
+        }
+    }
+
+    /// [`Protocol::detect_from`] this process's environment, `Auto` is resolved, others kept.
+    pub fn resolve(self) -> Self {
+        match self {
+            Self::Auto => Self::detect_from(|k| std::env::var(k).ok()),
+            p => p,
+        }
+    }
+}
+
+/// The image to preview, `what` can be a path, a time (or range, the last of which is used) see
+/// --oneshot, or unset for the newest disc we have.
+pub fn find_disc(uc: &Config, what: Option<&str>) -> Result<PathBuf> {
+    let archive = Archive::from_config(uc);
+    let Some(what) = what else {
+        // Newest by the frame it's of, not when it was saved, backfills are often of older ones.
+        let newest = archive
+            .entries()?
+            .into_iter()
+            .filter(|e| e.path.is_file())
+            .max_by(|a, b| a.timestamp.cmp(&b.timestamp))
+            .map(|e| e.path);
+        let newest = newest.or_else(|| {
+            let depth = uc.filename.depth();
+            let mut local = images_in(&uc.completed, depth).unwrap_or_default();
+            local.extend(images_in(&uc.backup, depth).unwrap_or_default());
+            local
+                .into_iter()
+                .filter_map(|p| Some((uc.filename.parse(&p).ok()?.hwdt, p)))
+                .max_by_key(|(hwdt, _)| *hwdt)
+                .map(|(_, p)| p)
+        });
+        return match newest {
+            Some(p) => Ok(p),
+            None => bail!("There aren't any discs in your archive, `completed` or `backup` yet"),
+        };
+    };
+
+    if Path::new(what).is_file() {
+        return Ok(what.into());
+    }
+    let Some(hwdt) = parse_when(what)?.last() else {
+        bail!("{what} has no frames in it");
+    };
+    match archive.local_disc(uc, hwdt)? {
+        Some(p) => Ok(p),
+        None => bail!("There's no disc for {hwdt} here, fetch it with --oneshot '{hwdt}' first"),
+    }
+}
+
+/// `img` as escape codes (or blocks) for `protocol`, `cols` wide.
+pub fn render(img: &DynamicImage, protocol: Protocol, cols: u32) -> Result<String> {
+    let cols = cols.max(1);
+    // Cells are about twice as tall as they're wide, so a square disc wants half as many rows.
+    let rows = cols.div_ceil(2);
+    let px = (cols * CELL_PX).min(MAX_PX);
+
+    Ok(match protocol.resolve() {
+        Protocol::Kitty => {
+            let b64 = STANDARD.encode(png_of(&img.thumbnail(px, px))?);
+            let chunks = b64.as_bytes().chunks(KITTY_CHUNK).collect::<Vec<_>>();
+            let mut out = String::new();
+            for (n, chunk) in chunks.iter().enumerate() {
+                let more = (n + 1 < chunks.len()) as u8;
+                let chunk = std::str::from_utf8(chunk)?;
+                match n {
+                    0 => write!(
+                        out,
+                        "\x1b_Gf=100,a=T,c={cols},r={rows},m={more};{chunk}\x1b\\"
+                    )?,
+                    _ => write!(out, "\x1b_Gm={more};{chunk}\x1b\\")?,
+                }
+            }
+            out.push('\n');
+            out
+        }
+        Protocol::Iterm => {
+            let png = png_of(&img.thumbnail(px, px))?;
+            format!(
+                "\x1b]1337;File=inline=1;size={};width={cols};preserveAspectRatio=1:{}\x07\n",
+                png.len(),
+                STANDARD.encode(&png)
+            )
+        }
+        Protocol::Sixel => sixel(&img.thumbnail(px, px).to_rgb8()),
+        Protocol::Blocks | Protocol::Auto => blocks(
+            &img.resize_exact(cols, rows * 2, FilterType::Triangle)
+                .to_rgb8(),
+        ),
+    })
+}
+
+fn png_of(img: &DynamicImage) -> Result<Vec<u8>> {
+    let mut out = Cursor::new(vec![]);
+    img.write_to(&mut out, ImageOutputFormat::Png)?;
+    Ok(out.into_inner())
+}
+
+/// Each cell is an upper half block, coloured as the px above, on a background of the px below.
+fn blocks(img: &RgbImage) -> String {
+    let mut out = String::new();
+    for y in (0..img.height()).step_by(2) {
+        for x in 0..img.width() {
+            let [r, g, b] = img.get_pixel(x, y).0;
+            _ = write!(out, "\x1b[38;2;{r};{g};{b}m");
+            if y + 1 < img.height() {
+                let [r, g, b] = img.get_pixel(x, y + 1).0;
+                _ = write!(out, "\x1b[48;2;{r};{g};{b}m");
+            }
+            out.push('▀');
+        }
+        out.push_str("\x1b[0m\n");
+    }
+    out
+}
+
+/// Which of the 216 colours (6 levels of each channel) `c` is closest to.
+fn cube_index(c: [u8; 3]) -> usize {
+    let level = |v: u8| (v as usize * 5 + 127) / 255;
+    level(c[0]) * 36 + level(c[1]) * 6 + level(c[2])
+}
+
+/// `img` as sixel, in a 216 colour palette which is plenty for a preview.
+fn sixel(img: &RgbImage) -> String {
+    let (w, h) = img.dimensions();
+    let mut out = format!("\x1bPq\"1;1;{w};{h}");
+    for i in 0..216 {
+        let pct = |l: usize| l * 100 / 5;
+        _ = write!(
+            out,
+            "#{i};2;{};{};{}",
+            pct(i / 36),
+            pct(i / 6 % 6),
+            pct(i % 6)
+        );
+    }
+
+    let indexed = img.pixels().map(|p| cube_index(p.0)).collect::<Vec<_>>();
+    for band in 0..h.div_ceil(6) {
+        let ys = band * 6..((band + 1) * 6).min(h);
+        let mut used = ys
+            .clone()
+            .flat_map(|y| (0..w).map(move |x| (y * w + x) as usize))
+            .map(|i| indexed[i])
+            .collect::<Vec<_>>();
+        used.sort_unstable();
+        used.dedup();
+
+        for (n, colour) in used.iter().enumerate() {
+            if n > 0 {
+                out.push('$');
+            }
+            _ = write!(out, "#{colour}");
+            let sixels = (0..w).map(|x| {
+                let bits = ys
+                    .clone()
+                    .enumerate()
+                    .filter(|(_, y)| indexed[(y * w + x) as usize] == *colour)
+                    .fold(0u8, |bits, (bit, _)| bits | 1 << bit);
+                (63 + bits) as char
+            });
+            push_runs(&mut out, sixels);
+        }
+        out.push('-');
+    }
+    out.push_str("\x1b\\\n");
+    out
+}
+
+/// Run length encoded, as sixel likes it: `!<n><char>` for anything repeated more than 3 times.
+fn push_runs(out: &mut String, chars: impl Iterator<Item = char>) {
+    let mut run: Option<(char, usize)> = None;
+    let flush = |out: &mut String, (c, n): (char, usize)| match n {
+        1..=3 => (0..n).for_each(|_| out.push(c)),
+        _ => _ = write!(out, "!{n}{c}"),
+    };
+    for c in chars {
+        run = match run {
+            Some((prev, n)) if prev == c => Some((c, n + 1)),
+            Some(prev) => {
+                flush(out, prev);
+                Some((c, 1))
+            }
+            None => Some((c, 1)),
+        };
+    }
+    if let Some(last) = run {
+        flush(out, last);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> Protocol {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        Protocol::detect_from(|k| vars.get(k).map(|v| v.to_string()))
+    }
+
+    #[test]
+    fn detection() {
+        assert_eq!(env(&[("TERM", "xterm-kitty")]), Protocol::Kitty);
+        assert_eq!(
+            env(&[("TERM", "xterm-256color"), ("LC_TERMINAL", "iTerm2")]),
+            Protocol::Iterm
+        );
+        assert_eq!(env(&[("TERM", "foot")]), Protocol::Sixel);
+        assert_eq!(env(&[("TERM", "xterm-256color")]), Protocol::Blocks);
+        assert_eq!(env(&[]), Protocol::Blocks);
+        assert_eq!(Protocol::Sixel.resolve(), Protocol::Sixel);
+    }
+
+    #[test]
+    fn rendering() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(2, 2, |x, y| match (x, y) {
+            (0, 0) => Rgb([255, 0, 0]),
+            (0, 1) => Rgb([0, 0, 255]),
+            _ => Rgb([0, 0, 0]),
+        }));
+        assert_eq!(
+            blocks(&img.to_rgb8()),
+            "\x1b[38;2;255;0;0m\x1b[48;2;0;0;255m▀\x1b[38;2;0;0;0m\x1b[48;2;0;0;0m▀\x1b[0m\n"
+        );
+
+        // Red is #180 in the cube, black #0, and the whole 2px high image fits in one band.
+        let s = sixel(&img.to_rgb8());
+        assert!(s.starts_with("\x1bPq\"1;1;2;2#0;2;0;0;0"));
+        assert!(s.ends_with("#0?B$#5A?$#180@?-\x1b\\\n"), "{s:?}");
+
+        let mut runs = String::new();
+        push_runs(&mut runs, "aaaaabbbc".chars());
+        assert_eq!(runs, "!5abbbc");
+
+        let kitty = render(&img, Protocol::Kitty, 4).unwrap();
+        assert!(kitty.starts_with("\x1b_Gf=100,a=T,c=4,r=2,m=0;"));
+        let iterm = render(&img, Protocol::Iterm, 4).unwrap();
+        assert!(iterm.starts_with("\x1b]1337;File=inline=1;size="));
+    }
+
+    #[test]
+    fn newest_is_by_frame_not_mtime() {
+        let dir = std::env::temp_dir().join("rustwari_newest_is_by_frame_not_mtime");
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let uc: Config =
+            serde_yaml::from_str(&format!("completed: {0}\nbackup: {0}", dir.display())).unwrap();
+
+        // Backfilled after the newer one was fetched.
+        std::fs::write(dir.join("fulldisc-2022-09-21 00_20.png"), b"").unwrap();
+        std::fs::write(dir.join("fulldisc-2022-09-21 00_10.png"), b"").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(dir.join("fulldisc-2022-09-21 00_20.png"))
+            .unwrap()
+            .set_modified(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap();
+        assert_eq!(
+            find_disc(&uc, None).unwrap(),
+            dir.join("fulldisc-2022-09-21 00_20.png")
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,245 @@
+//! Reporting progress, without assuming there's a terminal to draw on.
+//!
+//! Much like the `log` crate there's one sink for the whole process, set with [`set_sink`], until
+//! that happens events are dropped, so embedding rustwari elsewhere (a GUI, systemd) stays quiet.
+
+use clap::ValueEnum;
+use kdam::term::Colorizer;
+use kdam::{tqdm, Bar, BarExt};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Something that happened on the way to a new wallpaper.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    TileQueued {
+        x: u32,
+        y: u32,
+    },
+    TileDownloaded {
+        x: u32,
+        y: u32,
+        bytes: usize,
+    },
+    TileFailed {
+        x: u32,
+        y: u32,
+        attempt: u32,
+        error: String,
+        /// No more attempts will be made.
+        gave_up: bool,
+    },
+    /// The tile never arrived, so the one from `from` was used instead.
+    TileSubstituted {
+        x: u32,
+        y: u32,
+        from: String,
+    },
+    /// The tile has been placed into the disc being assembled.
+    TileAssembled {
+        x: u32,
+        y: u32,
+    },
+    /// A finished image is on disk.
+    Saved {
+        path: PathBuf,
+    },
+    /// ..and is now the wallpaper.
+    Set {
+        path: PathBuf,
+    },
+}
+
+/// Receives every [`ProgressEvent`], events can come from many tasks at once.
+pub trait ProgressSink: Send + Sync {
+    fn event(&self, e: &ProgressEvent);
+}
+
+/// The sinks available from the CLI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ProgressStyle {
+    /// A progress bar, on stderr.
+    Bar,
+    Silent,
+    /// Through the logger, see `--verbose`.
+    Log,
+    /// One JSON object per event, on stdout.
+    Json,
+}
+
+impl ProgressStyle {
+    pub fn sink(&self) -> Arc<dyn ProgressSink> {
+        match self {
+            ProgressStyle::Bar => Arc::new(TerminalBar::default()),
+            ProgressStyle::Silent => Arc::new(Silent),
+            ProgressStyle::Log => Arc::new(LogLines),
+            ProgressStyle::Json => Arc::new(JsonLines::new(std::io::stdout())),
+        }
+    }
+}
+
+static SINK: RwLock<Option<Arc<dyn ProgressSink>>> = RwLock::new(None);
+
+/// Send all future events to `sink`.
+pub fn set_sink(sink: Arc<dyn ProgressSink>) {
+    *SINK.write().expect("progress sink lock poisoned") = Some(sink);
+}
+
+/// Report `e` to whichever sink is set.
+pub fn emit(e: ProgressEvent) {
+    if let Some(sink) = SINK.read().expect("progress sink lock poisoned").as_ref() {
+        sink.event(&e);
+    }
+}
+
+/// Ignores everything.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Silent;
+
+impl ProgressSink for Silent {
+    fn event(&self, _: &ProgressEvent) {}
+}
+
+/// I like to see progress bars.
+#[derive(Default)]
+pub struct TerminalBar {
+    bar: Mutex<Option<Bar>>,
+}
+
+impl ProgressSink for TerminalBar {
+    fn event(&self, e: &ProgressEvent) {
+        let mut bar = self.bar.lock().expect("progress bar lock poisoned");
+        match e {
+            ProgressEvent::TileAssembled { .. } => {
+                let pb = bar.get_or_insert_with(|| {
+                    tqdm!(
+                        total = 400,
+                        bar_format = format!(
+                            "Progress: {{animation}} {}",
+                            "{percentage:3.0}%".colorize("#EE6FF8")
+                        ),
+                        colour = "gradient(#5A56E0,#EE6FF8)",
+                        force_refresh = true
+                    )
+                });
+                pb.update(1);
+            }
+            ProgressEvent::Saved { .. } => {
+                if let Some(pb) = bar.take() {
+                    pb.completed();
+                    eprintln!(); // Creating space for the progressbars.
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Everything goes to the logger, the per-tile chatter at debug.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogLines;
+
+impl ProgressSink for LogLines {
+    fn event(&self, e: &ProgressEvent) {
+        match e {
+            ProgressEvent::TileQueued { x, y } => debug!("Queued x{x}, y{y}"),
+            ProgressEvent::TileDownloaded { x, y, bytes } => {
+                debug!("Downloaded x{x}, y{y} ({bytes} bytes)")
+            }
+            ProgressEvent::TileFailed {
+                x,
+                y,
+                attempt,
+                error,
+                gave_up,
+            } => warn!(
+                "x{x}, y{y} failed (attempt {attempt}{}): {error}",
+                if *gave_up { ", giving up" } else { "" }
+            ),
+            ProgressEvent::TileSubstituted { x, y, from } => {
+                warn!("x{x}, y{y} is missing, using the one from {from}")
+            }
+            ProgressEvent::TileAssembled { x, y } => debug!("Assembled x{x}, y{y}"),
+            ProgressEvent::Saved { path } => info!("Saved {}", path.display()),
+            ProgressEvent::Set { path } => info!("Set {} as the wallpaper", path.display()),
+        }
+    }
+}
+
+/// One JSON object per line, for other programs to follow along with.
+pub struct JsonLines {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl JsonLines {
+    pub fn new<W: Write + Send + 'static>(w: W) -> Self {
+        Self {
+            out: Mutex::new(Box::new(w)),
+        }
+    }
+}
+
+impl ProgressSink for JsonLines {
+    fn event(&self, e: &ProgressEvent) {
+        let mut out = self.out.lock().expect("progress output lock poisoned");
+        if let Ok(line) = serde_json::to_string(e) {
+            _ = writeln!(out, "{line}");
+            _ = out.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Somewhere to write that we can read back.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_lines_are_tagged() {
+        let out = Shared::default();
+        let sink = JsonLines::new(out.clone());
+        sink.event(&ProgressEvent::TileDownloaded {
+            x: 1,
+            y: 2,
+            bytes: 3,
+        });
+        sink.event(&ProgressEvent::Saved {
+            path: "/tmp/fulldisc.png".into(),
+        });
+
+        let written = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let lines = written.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                r#"{"event":"tile_downloaded","x":1,"y":2,"bytes":3}"#,
+                r#"{"event":"saved","path":"/tmp/fulldisc.png"}"#
+            ]
+        );
+        let back: ProgressEvent = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(
+            back,
+            ProgressEvent::TileDownloaded {
+                x: 1,
+                y: 2,
+                bytes: 3
+            }
+        );
+    }
+}
@@ -0,0 +1,309 @@
+//! Keeps the `completed` and `backup` dirs from growing forever.
+
+use crate::archive::Archive;
+use crate::filenames::FilenameTemplate;
+use crate::fileutils::images_in;
+use crate::palette::sidecars;
+use crate::server::RENDITIONS;
+use crate::user_config::Config;
+
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// How coarse thinning should be, once images are older than `thin_after_days`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThinTo {
+    Hour,
+    Day,
+}
+
+/// The rules for what's kept in a directory, anything left unset is not enforced.
+/// NOTE: The newest image is always kept, it's most likely your current wallpaper.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    /// Keep at most this many images.
+    pub keep_last: Option<usize>,
+    /// Remove the oldest images until the directory is under this size.
+    pub max_total_mb: Option<u64>,
+    /// Remove anything older than this.
+    pub max_age_days: Option<i64>,
+    /// Past this age only one image per `thin_to` is kept.
+    pub thin_after_days: Option<i64>,
+    pub thin_to: Option<ThinTo>,
+}
+
+/// Policies for each of the directories we manage, lives under `retention:` in the config.yml
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Retention {
+    pub completed: RetentionPolicy,
+    /// Backfilled discs are in here too, so this applies to them as well.
+    pub backup: RetentionPolicy,
+}
+
+/// An image on disk that a policy may decide to remove.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub path: PathBuf,
+    pub taken: DateTime<Utc>,
+    pub size: u64,
+}
+
+impl RetentionPolicy {
+    /// Works out which of `files` fall foul of the policy, nothing is touched on disk.
+    pub fn select_for_deletion(
+        &self,
+        mut files: Vec<Candidate>,
+        now: DateTime<Utc>,
+    ) -> Vec<Candidate> {
+        files.sort_by_key(|f| std::cmp::Reverse(f.taken)); // Newest first.
+
+        let mut doomed: HashSet<usize> = HashSet::new();
+
+        if let (Some(after), Some(thin_to)) = (self.thin_after_days, self.thin_to) {
+            let threshold = now - Duration::days(after);
+            let fmt = match thin_to {
+                ThinTo::Hour => "%Y-%m-%d %H",
+                ThinTo::Day => "%Y-%m-%d",
+            };
+            let mut seen = HashSet::new();
+            // Being newest first, the first we see in each bucket is the one we keep.
+            for (i, f) in files.iter().enumerate() {
+                if f.taken < threshold && !seen.insert(f.taken.format(fmt).to_string()) {
+                    doomed.insert(i);
+                }
+            }
+        }
+
+        if let Some(days) = self.max_age_days {
+            let threshold = now - Duration::days(days);
+            files
+                .iter()
+                .enumerate()
+                .filter(|(_, f)| f.taken < threshold)
+                .for_each(|(i, _)| _ = doomed.insert(i));
+        }
+
+        if let Some(n) = self.keep_last {
+            (0..files.len())
+                .filter(|i| !doomed.contains(i))
+                .skip(n)
+                .collect::<Vec<_>>()
+                .into_iter()
+                .for_each(|i| _ = doomed.insert(i));
+        }
+
+        if let Some(mb) = self.max_total_mb {
+            let mut total = 0;
+            for i in (0..files.len())
+                .filter(|i| !doomed.contains(i))
+                .collect::<Vec<_>>()
+            {
+                total += files[i].size;
+                if total > mb * 1024 * 1024 {
+                    doomed.insert(i);
+                }
+            }
+        }
+
+        doomed.remove(&0);
+
+        files
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| doomed.contains(i))
+            .map(|(_, f)| f)
+            .collect()
+    }
+}
+
+/// Dirs of ours, inside `completed`/`backup`, that hold something other than discs.
+const NOT_DISCS: [&str; 3] = [RENDITIONS, "tiles", "tile-cache"];
+
+/// Every disc in `dir`, laid out as `template` has them, dated by the frame they're of (or when
+/// they were last modified, for those from some other template).
+pub fn candidates<P: AsRef<Path>>(dir: P, template: &FilenameTemplate) -> Result<Vec<Candidate>> {
+    let dir = dir.as_ref();
+    let mut files = vec![];
+    if !dir.is_dir() {
+        return Ok(files);
+    }
+    for path in images_in(dir, template.depth())? {
+        let ours = path.strip_prefix(dir).is_ok_and(|rel| {
+            rel.components()
+                .any(|c| NOT_DISCS.iter().any(|n| c.as_os_str() == *n))
+        });
+        if ours {
+            continue;
+        }
+        let md = std::fs::metadata(&path)?;
+        let taken = match template.parse(&path) {
+            Ok(name) => name.hwdt.datetime(),
+            Err(_) => md.modified()?.into(),
+        };
+        files.push(Candidate {
+            taken,
+            size: md.len(),
+            path,
+        });
+    }
+    Ok(files)
+}
+
+/// Apply `policy` to the discs in `dir`, returning what was (or with `dry_run`, would have been)
+/// removed.
+pub fn prune_dir<P: AsRef<Path>>(
+    dir: P,
+    template: &FilenameTemplate,
+    policy: &RetentionPolicy,
+    dry_run: bool,
+) -> Result<Vec<Candidate>> {
+    let doomed = policy.select_for_deletion(candidates(&dir, template)?, Utc::now());
+
+    if !dry_run {
+        for c in doomed.iter() {
+            match std::fs::remove_file(&c.path) {
+                Ok(_) => {
+                    debug!("Pruned: {}", c.path.display());
+                    for s in sidecars(&c.path).iter().filter(|s| s.is_file()) {
+                        _ = std::fs::remove_file(s);
+                    }
+                }
+                Err(e) => warn!("Unable to prune {}: {e}", c.path.display()),
+            }
+        }
+    }
+    Ok(doomed)
+}
+
+/// Apply the configured retention policies to both the `completed` and `backup` dirs.
+pub fn prune(uc: &Config, dry_run: bool) -> Result<Vec<Candidate>> {
+    let template = &uc.filename;
+    let mut removed = prune_dir(&uc.completed, template, &uc.retention.completed, dry_run)?;
+    removed.extend(prune_dir(
+        &uc.backup,
+        template,
+        &uc.retention.backup,
+        dry_run,
+    )?);
+
+    if !dry_run && !removed.is_empty() {
+        let paths = removed.iter().map(|c| c.path.clone()).collect::<Vec<_>>();
+        Archive::from_config(uc).forget(&paths)?;
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 6, 10, 12, 0, 0).unwrap()
+    }
+
+    /// An image every 10 minutes going back `hours`, newest first.
+    fn every_ten_minutes(hours: i64) -> Vec<Candidate> {
+        (0..hours * 6)
+            .map(|i| Candidate {
+                path: PathBuf::from(format!("{i}.png")),
+                taken: now() - Duration::minutes(10 * i),
+                size: 1024 * 1024,
+            })
+            .collect()
+    }
+
+    fn names(c: &[Candidate]) -> Vec<String> {
+        c.iter().map(|c| c.path.display().to_string()).collect()
+    }
+
+    #[test]
+    fn empty_policy_keeps_everything() {
+        let policy = RetentionPolicy::default();
+        assert!(policy
+            .select_for_deletion(every_ten_minutes(48), now())
+            .is_empty());
+    }
+
+    #[test]
+    fn keep_last_and_max_size() {
+        let policy = RetentionPolicy {
+            keep_last: Some(10),
+            ..Default::default()
+        };
+        let doomed = policy.select_for_deletion(every_ten_minutes(2), now());
+        assert_eq!(doomed.len(), 2);
+        assert_eq!(names(&doomed), vec!["10.png", "11.png"]);
+
+        let policy = RetentionPolicy {
+            max_total_mb: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(
+            policy
+                .select_for_deletion(every_ten_minutes(2), now())
+                .len(),
+            9
+        );
+    }
+
+    #[test]
+    fn thinning_keeps_one_per_bucket() {
+        let policy = RetentionPolicy {
+            thin_after_days: Some(1),
+            thin_to: Some(ThinTo::Hour),
+            ..Default::default()
+        };
+        let files = every_ten_minutes(48);
+        let total = files.len();
+        let doomed = policy.select_for_deletion(files, now());
+
+        // The most recent day (inclusive) is untouched, the day before is thinned to one per hour.
+        assert_eq!(total - doomed.len(), (24 * 6 + 1) + 24);
+    }
+
+    #[test]
+    fn newest_is_never_removed() {
+        let policy = RetentionPolicy {
+            keep_last: Some(0),
+            max_age_days: Some(0),
+            ..Default::default()
+        };
+        let doomed = policy.select_for_deletion(every_ten_minutes(1), now());
+        assert!(!names(&doomed).contains(&"0.png".to_string()));
+        assert_eq!(doomed.len(), 5);
+    }
+
+    #[test]
+    fn discs_are_dated_by_their_frame() {
+        let dir = std::env::temp_dir().join("rustwari_discs_are_dated_by_their_frame");
+        let template: FilenameTemplate = "{year}/{month}-{day} {hour}_{minute}.{ext}"
+            .parse()
+            .unwrap();
+        for p in [
+            "2023/06-10 12_00.png",
+            "2023/stray.png",
+            "renditions/latest.jpg",
+            "tiles/2023-06-10 1200/0_0.png",
+        ] {
+            std::fs::create_dir_all(dir.join(p).parent().unwrap()).unwrap();
+            std::fs::write(dir.join(p), b"").unwrap();
+        }
+
+        let mut found = candidates(&dir, &template).unwrap();
+        found.sort_by_key(|c| c.taken);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].path, dir.join("2023/06-10 12_00.png"));
+        assert_eq!(found[0].taken, now());
+        // Not one of ours, so it's as old as it was last modified.
+        assert_eq!(found[1].path, dir.join("2023/stray.png"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,399 @@
+//! `rustwari serve`, one instance does the fetching and shares the result with everyone else.
+//!
+//! - `/latest.jpg` the latest disc, as a (resized) jpeg
+//! - `/latest.json` metadata about it
+//! - `/frames/{timestamp}` anything in the archive, i.e `/frames/2022-09-21T00:10`
+//! - `/metrics` for Prometheus
+
+use crate::archive::{checksum, Archive, ArchiveEntry};
+use crate::cli::Cli;
+use crate::cvutils::{assemble_full_disc, assemble_to_with, OutputFormat, OutputSettings, COLMAX};
+use crate::himawaridt::HimawariDatetime;
+use crate::sources::TileSource;
+use crate::tiles::{fetch_tiles, replay, FetchBudget, TileFallback};
+use crate::user_config::Config;
+
+use anyhow::Result;
+use bytes::Bytes;
+use hyper::header::{CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{debug, info};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tokio_util::io::ReaderStream;
+
+/// Renditions live in here, inside the `completed` dir.
+pub const RENDITIONS: &str = "renditions";
+
+/// Everything a client needs to know about the latest disc.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LatestMeta {
+    /// YYYY-MM-DD HH:MM (UTC)
+    pub timestamp: String,
+    pub source: String,
+    pub product: String,
+    /// Of the jpeg at `/latest.jpg`
+    pub width: u32,
+    pub height: u32,
+    pub size: u64,
+    pub sha256: String,
+    /// Where the full sized disc can be had, if it's been archived.
+    pub frame: String,
+}
+
+/// The latest disc, ready to hand out.
+#[derive(Debug, Clone)]
+pub struct Latest {
+    pub meta: LatestMeta,
+    pub jpeg: Bytes,
+}
+
+pub type SharedLatest = Arc<RwLock<Option<Latest>>>;
+
+/// The archive by timestamp, reloaded after each refresh rather than read for every request.
+pub type SharedFrames = Arc<RwLock<HashMap<String, ArchiveEntry>>>;
+
+/// Replace `frames` with what's in `archive` now, the newest entry for a timestamp wins.
+pub async fn reload_frames(archive: &Archive, frames: &SharedFrames) -> Result<()> {
+    let by_timestamp = archive
+        .entries()?
+        .into_iter()
+        .map(|e| (e.timestamp.clone(), e))
+        .collect();
+    *frames.write().await = by_timestamp;
+    Ok(())
+}
+
+/// How `serve` should behave.
+#[derive(Debug, Clone)]
+pub struct ServeOpts {
+    pub bind: SocketAddr,
+    /// The size (in px) of the jpeg rendition.
+    pub size: u32,
+    pub quality: u8,
+}
+
+/// Fetch the latest disc, assemble it (as per the config.yml) along with its jpeg rendition, then
+/// publish it to `latest`.
+pub async fn refresh(
+    client: &Client,
+    uc: &Config,
+    cli: &Cli,
+    opts: &ServeOpts,
+    latest: &SharedLatest,
+) -> Result<()> {
+    let hwdt = HimawariDatetime::closest_to_now();
+    let source = TileSource::from_config(uc)?;
+
+    // Fetched once, assembled twice.
+    let mut budget = FetchBudget::unlimited();
+    budget.retries = uc.fallback.retries;
+    let fallback = TileFallback::from_config(uc, hwdt);
+    let tiles = fetch_tiles(client, &source, hwdt, budget).await?;
+    let fulldisc =
+        assemble_full_disc(hwdt, uc, cli, replay(tiles.clone()), Some(&fallback)).await?;
+
+    let dir = Path::new(&uc.completed).join(RENDITIONS);
+    std::fs::create_dir_all(&dir)?;
+    let out = OutputSettings {
+        format: OutputFormat::Jpeg,
+        quality: opts.quality,
+        rgb: true,
+        ..Default::default()
+    };
+    let p = dir.join("latest.jpg");
+    let rendition =
+        assemble_to_with(&p, opts.size / COLMAX, out, replay(tiles), Some(&fallback)).await?;
+
+    let jpeg = Bytes::from(tokio::fs::read(&p).await?);
+    let meta = LatestMeta {
+        frame: format!("/frames/{}", hwdt.datetime().format("%Y-%m-%dT%H:%M")),
+        timestamp: hwdt.to_string(),
+        source: source.name,
+        product: source.product,
+        width: rendition.width,
+        height: rendition.height,
+        size: jpeg.len() as u64,
+        sha256: checksum(&p)?,
+    };
+    info!(
+        "Serving {} ({})",
+        meta.timestamp,
+        fulldisc
+            .path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+    );
+
+    *latest.write().await = Some(Latest { meta, jpeg });
+    Ok(())
+}
+
+/// Run the HTTP server until the process exits.
+pub async fn serve_http(
+    addr: SocketAddr,
+    latest: SharedLatest,
+    frames: SharedFrames,
+) -> Result<()> {
+    let make_svc = make_service_fn(move |_| {
+        let latest = latest.clone();
+        let frames = frames.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                handle(req, latest.clone(), frames.clone())
+            }))
+        }
+    });
+
+    info!("Listening on http://{addr}");
+    Server::try_bind(&addr)?.serve(make_svc).await?;
+    Ok(())
+}
+
+/// Route a single request.
+pub async fn handle(
+    req: Request<Body>,
+    latest: SharedLatest,
+    frames: SharedFrames,
+) -> Result<Response<Body>, Infallible> {
+    debug!("{} {}", req.method(), req.uri());
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+    }
+
+    let res = match req.uri().path() {
+        "/latest.jpg" | "/latest.json" => match latest.read().await.as_ref() {
+            None => status(StatusCode::SERVICE_UNAVAILABLE),
+            Some(l) if req.uri().path() == "/latest.jpg" => {
+                let jpeg = l.jpeg.clone();
+                cached(
+                    &req,
+                    &l.meta.sha256,
+                    "image/jpeg",
+                    jpeg.len() as u64,
+                    || jpeg.into(),
+                )
+            }
+            Some(l) => {
+                let json = serde_json::to_vec(&l.meta).unwrap_or_default();
+                cached(
+                    &req,
+                    &l.meta.sha256,
+                    "application/json",
+                    json.len() as u64,
+                    || json.into(),
+                )
+            }
+        },
+        "/metrics" => crate::metrics::response(),
+        path if path.starts_with("/frames/") => {
+            let query = path.trim_start_matches("/frames/");
+            match frame_timestamp(query) {
+                Some(ts) => match frames.read().await.get(&ts).cloned() {
+                    Some(e) => frame(&req, &e).await,
+                    None => status(StatusCode::NOT_FOUND),
+                },
+                None => status(StatusCode::BAD_REQUEST),
+            }
+        }
+        _ => status(StatusCode::NOT_FOUND),
+    };
+    Ok(res)
+}
+
+/// An archived disc, streamed from disk as they're far too big to read in for every request.
+async fn frame(req: &Request<Body>, e: &ArchiveEntry) -> Response<Body> {
+    let fd = match tokio::fs::File::open(&e.path).await {
+        Ok(fd) => fd,
+        Err(_) => return status(StatusCode::NOT_FOUND),
+    };
+    let len = match fd.metadata().await {
+        Ok(m) => m.len(),
+        Err(_) => return status(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    cached(req, &e.sha256, content_type(&e.path), len, || {
+        Body::wrap_stream(ReaderStream::new(fd))
+    })
+}
+
+/// A response with an ETag, or a 304 if the client already has it.
+fn cached<F: FnOnce() -> Body>(
+    req: &Request<Body>,
+    sha256: &str,
+    content_type: &str,
+    len: u64,
+    body: F,
+) -> Response<Body> {
+    let etag = format!("\"{sha256}\"");
+    let builder = Response::builder()
+        .header(ETAG, &etag)
+        .header(CACHE_CONTROL, "public, max-age=60");
+
+    let fresh = req
+        .headers()
+        .get(IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|t| t.trim() == etag || t.trim() == "*"));
+    if fresh {
+        return builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .expect("valid response");
+    }
+
+    let body = match req.method() == Method::HEAD {
+        true => Body::empty(),
+        false => body(),
+    };
+    builder
+        .header(CONTENT_TYPE, content_type)
+        .header(CONTENT_LENGTH, len)
+        .body(body)
+        .expect("valid response")
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .body(Body::from(code.canonical_reason().unwrap_or_default()))
+        .expect("valid response")
+}
+
+fn content_type(p: &Path) -> &'static str {
+    match p.extension().and_then(|e| e.to_str()) {
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        _ => "image/png",
+    }
+}
+
+/// Turns `2022-09-21T00:10` or `202209210010` into the archive's `2022-09-21 00:10`.
+pub fn frame_timestamp(query: &str) -> Option<String> {
+    query
+        .replace("%20", " ")
+        .parse::<HimawariDatetime>()
+        .ok()
+        .map(|hwdt| hwdt.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn latest() -> SharedLatest {
+        Arc::new(RwLock::new(Some(Latest {
+            meta: LatestMeta {
+                timestamp: "2022-09-21 00:10".into(),
+                source: "himawari8".into(),
+                product: "D531106".into(),
+                width: 5120,
+                height: 5120,
+                size: 4,
+                sha256: "abc".into(),
+                frame: "/frames/2022-09-21T00:10".into(),
+            },
+            jpeg: Bytes::from_static(b"jpeg"),
+        })))
+    }
+
+    fn get(path: &str, etag: Option<&str>) -> Request<Body> {
+        let mut req = Request::get(path);
+        if let Some(etag) = etag {
+            req = req.header(IF_NONE_MATCH, etag);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn latest_is_cached_by_etag() {
+        let frames = SharedFrames::default();
+
+        let res = handle(get("/latest.jpg", None), latest(), frames.clone())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[ETAG], "\"abc\"");
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, Bytes::from_static(b"jpeg"));
+
+        let res = handle(
+            get("/latest.jpg", Some("\"abc\"")),
+            latest(),
+            frames.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+        let res = handle(get("/latest.json", None), latest(), frames.clone())
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let meta: LatestMeta = serde_json::from_slice(&body).unwrap();
+        assert_eq!(meta.timestamp, "2022-09-21 00:10");
+
+        let empty = Arc::new(RwLock::new(None));
+        let res = handle(get("/latest.jpg", None), empty, frames.clone())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let res = handle(get("/frames/2022-09-21T00:10", None), latest(), frames)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn frames_are_streamed_from_the_archive() {
+        let dir = std::env::temp_dir().join("rustwari_frames_are_streamed_from_the_archive");
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let p = dir.join("fulldisc-2022-09-21 00_10.png");
+        std::fs::write(&p, b"not really a png").unwrap();
+        let archive = Archive::new(dir.join("archive.jsonl"));
+        archive
+            .record(&ArchiveEntry {
+                timestamp: "2022-09-21 00:10".into(),
+                source: "himawari8".into(),
+                product: "D531106".into(),
+                width: 5120,
+                height: 5120,
+                path: p,
+                size: 16,
+                sha256: "def".into(),
+            })
+            .unwrap();
+
+        let frames = SharedFrames::default();
+        reload_frames(&archive, &frames).await.unwrap();
+        let res = handle(get("/frames/2022-09-21T00:10", None), latest(), frames)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_LENGTH], "16");
+        assert_eq!(res.headers()[ETAG], "\"def\"");
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, Bytes::from_static(b"not really a png"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn frame_timestamps() {
+        let expected = Some("2022-09-21 00:10".to_string());
+        assert_eq!(frame_timestamp("2022-09-21T00:10"), expected);
+        assert_eq!(frame_timestamp("2022-09-21%2000:10"), expected);
+        assert_eq!(frame_timestamp("202209210010"), expected);
+        assert_eq!(frame_timestamp("yesterday"), None);
+    }
+}
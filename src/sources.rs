@@ -0,0 +1,162 @@
+//! Where tiles come from, NICT by default, but any HTTP mirror or `file://` directory laid out the
+//! same way will do.
+
+use crate::himawaridt::{HimawariDatetime, PRODUCT, SOURCE, URLBASE};
+use crate::peer::PEER;
+use crate::user_config::Config;
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+/// The default layout of tiles beneath the base url.
+pub const PATH_TEMPLATE: &str = "{year}/{month}/{day}/{hour}{minute}00_{x}_{y}.png";
+
+/// A source as described in the `sources:` section of the config.yml
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SourceSettings {
+    /// http(s)://, file:// or a plain path to a directory.
+    pub base_url: String,
+    /// Relative to `base_url`, one of {year} {month} {day} {hour} {minute} {x} {y}
+    pub path_template: String,
+    pub product: String,
+}
+
+impl Default for SourceSettings {
+    fn default() -> Self {
+        Self {
+            base_url: URLBASE.into(),
+            path_template: PATH_TEMPLATE.into(),
+            product: PRODUCT.into(),
+        }
+    }
+}
+
+/// A resolved, ready to use, source of tiles.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TileSource {
+    pub name: String,
+    pub base_url: Url,
+    pub path_template: String,
+    pub product: String,
+}
+
+impl Default for TileSource {
+    fn default() -> Self {
+        Self::from_settings(SOURCE, &SourceSettings::default()).expect("URLBASE is a valid url")
+    }
+}
+
+impl TileSource {
+    pub fn from_settings(name: &str, settings: &SourceSettings) -> Result<Self> {
+        let base = settings.base_url.trim();
+        let mut base_url = match Url::parse(base) {
+            Ok(url) => url,
+            // Not a url, so hopefully a path.
+            Err(_) => Url::from_directory_path(std::fs::canonicalize(base)?)
+                .map_err(|_| anyhow!("Unable to use {base} as a directory"))?,
+        };
+        if !["http", "https", "file"].contains(&base_url.scheme()) {
+            bail!(
+                "Unsupported scheme for source {name}: {}",
+                base_url.scheme()
+            );
+        }
+        // Without the trailing / joining would replace the last segment.
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+
+        Ok(Self {
+            name: name.into(),
+            base_url,
+            path_template: settings.path_template.clone(),
+            product: settings.product.clone(),
+        })
+    }
+
+    /// The source selected by `source:` in the user's config.yml, when that's a peer this is
+    /// what we fall back to.
+    pub fn from_config(uc: &Config) -> Result<Self> {
+        match uc.sources.get(&uc.source) {
+            Some(settings) => Self::from_settings(&uc.source, settings),
+            None if uc.source == SOURCE || uc.source == PEER => Ok(Self::default()),
+            None => bail!("source: {} isn't defined in `sources:`", uc.source),
+        }
+    }
+
+    /// builds valid [`url`] for a single tile.
+    pub fn tile_url(&self, hwdt: &HimawariDatetime, x: u32, y: u32) -> Result<Url> {
+        let path = self
+            .path_template
+            .replace("{year}", &hwdt.year().to_string())
+            .replace("{month}", &format!("{:02}", hwdt.month()))
+            .replace("{day}", &format!("{:02}", hwdt.day()))
+            .replace("{hour}", &format!("{:02}", hwdt.hour()))
+            .replace("{minute}", &format!("{:02}", hwdt.minute()))
+            .replace("{x}", &x.to_string())
+            .replace("{y}", &y.to_string());
+
+        Ok(self.base_url.join(path.trim_start_matches('/'))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hwdt() -> HimawariDatetime {
+        "2022-09-21 00:10".parse().unwrap()
+    }
+
+    #[test]
+    fn default_matches_nict() {
+        assert_eq!(
+            TileSource::default().tile_url(&hwdt(), 3, 7).unwrap(),
+            hwdt().get_url(3, 7).unwrap()
+        );
+    }
+
+    #[test]
+    fn mirrors() {
+        let src = TileSource::from_settings(
+            "mirror",
+            &SourceSettings {
+                base_url: "https://mirror.corp/himawari".into(),
+                path_template: "{year}{month}{day}/{hour}{minute}/{x}-{y}.png".into(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            src.tile_url(&hwdt(), 1, 2).unwrap().as_str(),
+            "https://mirror.corp/himawari/20220921/0010/1-2.png"
+        );
+
+        let dir = std::env::temp_dir();
+        let src = TileSource::from_settings(
+            "local",
+            &SourceSettings {
+                base_url: dir.display().to_string(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let url = src.tile_url(&hwdt(), 0, 0).unwrap();
+        assert_eq!(url.scheme(), "file");
+        assert!(url
+            .to_file_path()
+            .unwrap()
+            .ends_with("2022/09/21/001000_0_0.png"));
+
+        assert!(TileSource::from_settings(
+            "ftp",
+            &SourceSettings {
+                base_url: "ftp://somewhere/".into(),
+                ..Default::default()
+            }
+        )
+        .is_err());
+    }
+}
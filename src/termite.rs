@@ -1,25 +1,312 @@
+//! Logging, to the terminal and to (rotated) files, see the `logging:` section of the config.yml
+
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 
-pub fn setup_logger(verbose: bool) -> Result<(), fern::InitError> {
-    let termite_path = format!("rustwari_{}.log", chrono::Local::now().format("%Y-%m-%d"));
-    if verbose {
-        fern::Dispatch::new()
-            .format(|out, message, record| {
+use chrono::{Local, NaiveDate};
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const PREFIX: &str = "rustwari_";
+
+/// Where, and how, logs are kept.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogSettings {
+    /// Log files go in here, set to `~` to not write any, see [`default_dir`] for where they go
+    /// otherwise.
+    pub dir: Option<String>,
+    /// A day's log is rolled over to `rustwari_YYYY-MM-DD.N.log` once it's this big.
+    pub max_size_mb: u64,
+    /// How many log files to keep, the oldest are removed first.
+    pub keep: usize,
+    /// One JSON object per line, rather than plain text, handy for log shippers.
+    pub json: bool,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self {
+            dir: default_dir(),
+            max_size_mb: 10,
+            keep: 14,
+            json: false,
+        }
+    }
+}
+
+/// The per-user place for logs, `$XDG_STATE_HOME/rustwari` (`~/.local/state/rustwari`) on linux
+/// etc, `~/Library/Logs/rustwari` on macOS and `%LOCALAPPDATA%\rustwari\logs` on windows. `None`
+/// (so no log files) should we not be able to work out where that is.
+pub fn default_dir() -> Option<String> {
+    default_dir_from(|k| std::env::var(k).ok().filter(|v| !v.is_empty()))
+}
+
+fn default_dir_from(var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let dir = if cfg!(windows) {
+        PathBuf::from(var("LOCALAPPDATA")?)
+            .join("rustwari")
+            .join("logs")
+    } else if cfg!(target_os = "macos") {
+        PathBuf::from(var("HOME")?).join("Library/Logs/rustwari")
+    } else {
+        match var("XDG_STATE_HOME") {
+            Some(state) => PathBuf::from(state).join("rustwari"),
+            None => PathBuf::from(var("HOME")?).join(".local/state/rustwari"),
+        }
+    };
+    Some(dir.display().to_string())
+}
+
+/// The level implied by `-v`s and `-q`s, each one moving a step from `Info`.
+pub fn level_from(verbose: u8, quiet: u8) -> LevelFilter {
+    const LEVELS: [LevelFilter; 6] = [
+        LevelFilter::Off,
+        LevelFilter::Error,
+        LevelFilter::Warn,
+        LevelFilter::Info,
+        LevelFilter::Debug,
+        LevelFilter::Trace,
+    ];
+    let i = (3 + verbose as i32 - quiet as i32).clamp(0, LEVELS.len() as i32 - 1);
+    LEVELS[i as usize]
+}
+
+/// Parses a `RUST_LOG` like `debug` or `info,rustwari::tiles=trace,reqwest=warn` into a default
+/// level, and levels for specific targets.
+pub fn parse_filters(s: &str) -> (Option<LevelFilter>, Vec<(String, LevelFilter)>) {
+    let mut default = None;
+    let mut targets = vec![];
+    for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        match part.split_once('=') {
+            Some((target, level)) => match LevelFilter::from_str(level) {
+                Ok(level) => targets.push((target.to_string(), level)),
+                Err(_) => eprintln!("Ignoring RUST_LOG entry: {part}"),
+            },
+            None => match LevelFilter::from_str(part) {
+                Ok(level) => default = Some(level),
+                Err(_) => eprintln!("Ignoring RUST_LOG entry: {part}"),
+            },
+        }
+    }
+    (default, targets)
+}
+
+/// Sets up logging at `level`, `RUST_LOG` (when set) has the final say.
+pub fn setup_logger(level: LevelFilter, settings: &LogSettings) -> Result<(), fern::InitError> {
+    let (env_default, targets) = std::env::var("RUST_LOG")
+        .map(|s| parse_filters(&s))
+        .unwrap_or_default();
+
+    let json = settings.json;
+    let mut dispatch = fern::Dispatch::new()
+        .format(move |out, message, record| {
+            if json {
+                out.finish(format_args!(
+                    "{}",
+                    serde_json::json!({
+                        "ts": Local::now().to_rfc3339(),
+                        "level": record.level().to_string(),
+                        "target": record.target(),
+                        "line": record.line(),
+                        "msg": message.to_string(),
+                    })
+                ))
+            } else {
                 out.finish(format_args!(
                     "{}[{}][{}][{}] {}",
-                    chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
+                    Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
                     record.level(),
                     record.target(),
                     record.line().unwrap_or(0),
                     message
                 ))
-            })
-            .level(log::LevelFilter::Debug)
-            .chain(fern::log_file(termite_path)?)
-            .chain(std::io::stdout())
-            .apply()?;
-        return Ok(());
+            }
+        })
+        .level(env_default.unwrap_or(level))
+        .chain(std::io::stderr());
+
+    for (target, level) in targets {
+        dispatch = dispatch.level_for(target, level);
+    }
+
+    if let Some(dir) = &settings.dir {
+        let file = RotatingFile::open(dir, settings.max_size_mb * 1024 * 1024, settings.keep)?;
+        dispatch = dispatch.chain(Box::new(file) as Box<dyn Write + Send>);
     }
+
+    dispatch.apply()?;
     Ok(())
 }
+
+/// A `rustwari_YYYY-MM-DD.log` that moves on to a new file every day, or when it gets too big,
+/// removing old ones as it goes.
+#[derive(Debug)]
+pub struct RotatingFile {
+    dir: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    day: NaiveDate,
+    file: File,
+    written: u64,
+}
+
+impl RotatingFile {
+    pub fn open<P: AsRef<Path>>(dir: P, max_bytes: u64, keep: usize) -> std::io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        let day = Local::now().date_naive();
+        let (file, written) = Self::open_day(&dir, day)?;
+        Ok(Self {
+            dir,
+            max_bytes,
+            keep,
+            day,
+            file,
+            written,
+        })
+    }
+
+    fn path_for(dir: &Path, day: NaiveDate) -> PathBuf {
+        dir.join(format!("{PREFIX}{}.log", day.format("%Y-%m-%d")))
+    }
+
+    fn open_day(dir: &Path, day: NaiveDate) -> std::io::Result<(File, u64)> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(Self::path_for(dir, day))?;
+        let written = file.metadata()?.len();
+        Ok((file, written))
+    }
+
+    /// Move on to a fresh file if it's a new day, or `incoming` more bytes would be too many.
+    fn rotate_if_needed(&mut self, today: NaiveDate, incoming: u64) -> std::io::Result<()> {
+        if today != self.day {
+            self.day = today;
+        } else if self.max_bytes > 0 && self.written > 0 && self.written + incoming > self.max_bytes
+        {
+            let current = Self::path_for(&self.dir, self.day);
+            let n = (1..)
+                .find(|n| !current.with_extension(format!("{n}.log")).exists())
+                .expect("there's always a free number");
+            std::fs::rename(&current, current.with_extension(format!("{n}.log")))?;
+        } else {
+            return Ok(());
+        }
+
+        self.file.flush()?;
+        (self.file, self.written) = Self::open_day(&self.dir, self.day)?;
+        self.remove_old()
+    }
+
+    fn write_on(&mut self, today: NaiveDate, buf: &[u8]) -> std::io::Result<usize> {
+        self.rotate_if_needed(today, buf.len() as u64)?;
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    /// Keep only the newest `keep` log files, the one being written to always among them.
+    fn remove_old(&self) -> std::io::Result<()> {
+        let current = Self::path_for(&self.dir, self.day);
+        let mut logs = std::fs::read_dir(&self.dir)?
+            .filter_map(|e| e.ok())
+            .filter(|e| {
+                let name = e.file_name().to_string_lossy().to_string();
+                name.starts_with(PREFIX) && name.ends_with(".log") && e.path() != current
+            })
+            .filter_map(|e| Some((e.metadata().ok()?.modified().ok()?, e.path())))
+            .collect::<Vec<_>>();
+        logs.sort_by(|a, b| b.cmp(a));
+
+        for (_, p) in logs.iter().skip(self.keep.saturating_sub(1)) {
+            std::fs::remove_file(p)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.write_on(Local::now().date_naive(), buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    #[test]
+    fn logs_go_in_the_users_state_dir() {
+        let env = |vars: &'static [(&str, &str)]| {
+            default_dir_from(|k| {
+                vars.iter()
+                    .find(|(v, _)| *v == k)
+                    .map(|(_, s)| s.to_string())
+            })
+        };
+        assert_eq!(
+            env(&[("HOME", "/home/h"), ("XDG_STATE_HOME", "/state")]).as_deref(),
+            Some("/state/rustwari")
+        );
+        assert_eq!(
+            env(&[("HOME", "/home/h")]).as_deref(),
+            Some("/home/h/.local/state/rustwari")
+        );
+        assert_eq!(env(&[]), None);
+    }
+
+    #[test]
+    fn levels() {
+        assert_eq!(level_from(0, 0), LevelFilter::Info);
+        assert_eq!(level_from(2, 0), LevelFilter::Trace);
+        assert_eq!(level_from(9, 0), LevelFilter::Trace);
+        assert_eq!(level_from(0, 1), LevelFilter::Warn);
+        assert_eq!(level_from(0, 9), LevelFilter::Off);
+
+        let (default, targets) = parse_filters("debug, rustwari::tiles=trace,nonsense");
+        assert_eq!(default, Some(LevelFilter::Debug));
+        assert_eq!(
+            targets,
+            vec![("rustwari::tiles".into(), LevelFilter::Trace)]
+        );
+    }
+
+    #[test]
+    fn rotates_by_size_and_day() {
+        let dir = std::env::temp_dir().join("rustwari_rotates_by_size_and_day");
+        _ = std::fs::remove_dir_all(&dir);
+        let count = || std::fs::read_dir(&dir).unwrap().count();
+
+        let mut f = RotatingFile::open(&dir, 10, 3).unwrap();
+        let today = f.day;
+        f.write_on(today, b"0123456789").unwrap();
+        assert_eq!(count(), 1);
+        f.write_on(today, b"rolled").unwrap();
+        assert_eq!(count(), 2);
+        assert!(RotatingFile::path_for(&dir, today)
+            .with_extension("1.log")
+            .is_file());
+
+        let tomorrow = today.succ_opt().unwrap();
+        f.write_on(tomorrow, b"tomorrow").unwrap();
+        assert_eq!(count(), 3);
+        assert!(RotatingFile::path_for(&dir, tomorrow).is_file());
+
+        // Over `keep`, so the oldest goes.
+        f.write_on(tomorrow, b"0123456789").unwrap();
+        assert_eq!(count(), 3);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
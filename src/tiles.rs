@@ -1,14 +1,75 @@
 use crate::cvutils::{COLMAX, ROWMAX};
+use crate::fileutils::AtomicFile;
 use crate::himawaridt::HimawariDatetime;
+use crate::metrics::metrics;
+use crate::progress::{self, ProgressEvent};
+use crate::sources::TileSource;
+use crate::user_config::Config;
 
 use anyhow::Result;
-use async_recursion::async_recursion;
 use bytes::Bytes;
 use image::DynamicImage;
-use log::error;
+use log::{error, warn};
 use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use tokio::{sync::mpsc::Sender, task::JoinHandle};
+use std::time::Duration;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// Limits on how hard we lean on the server, shared between every tile fetched with it.
+#[derive(Debug, Clone)]
+pub struct FetchBudget {
+    /// Caps the number of requests in flight.
+    permits: Arc<Semaphore>,
+    /// The minimum gap between requests starting, when rate limited.
+    spacing: Option<Duration>,
+    next_slot: Arc<tokio::sync::Mutex<Instant>>,
+    /// Give up on a tile after this many failed attempts, `None` keeps trying forever.
+    pub retries: Option<u32>,
+}
+
+impl FetchBudget {
+    /// No limits, and no giving up, which is what you want when it's the latest disc you're after.
+    pub fn unlimited() -> Self {
+        Self::new(Semaphore::MAX_PERMITS, None, None)
+    }
+
+    pub fn new(max_in_flight: usize, per_second: Option<u32>, retries: Option<u32>) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max_in_flight.max(1))),
+            spacing: per_second.map(|n| Duration::from_secs_f64(1.0 / n.max(1) as f64)),
+            next_slot: Arc::new(tokio::sync::Mutex::new(Instant::now())),
+            retries,
+        }
+    }
+
+    /// Wait until the budget allows another request, hold onto the permit until it's done.
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("FetchBudget semaphore is never closed");
+
+        if let Some(spacing) = self.spacing {
+            let mut next = self.next_slot.lock().await;
+            let now = Instant::now();
+            if *next > now {
+                tokio::time::sleep_until(*next).await;
+            }
+            *next = (*next).max(now) + spacing;
+        }
+        permit
+    }
+}
 
 /// Use the [`tokio`] runtime to fetch tiles in green threads.
 /// Useful for getting mutiple tiles at once, use [`download_image`] for one offs.
@@ -17,22 +78,61 @@ pub async fn tokio_tile_fetcher(
     client: &Client,
     handles: &Arc<Mutex<Vec<tokio::task::JoinHandle<()>>>>,
     tx: Sender<(Bytes, RemoteTile)>,
+    budget: FetchBudget,
 ) -> Result<(), reqwest::Error> {
     let client_c = client.clone();
+    progress::emit(ProgressEvent::TileQueued { x: rt.x, y: rt.y });
 
     let handle = tokio::spawn(async move {
-        match rt.download_image(&client_c).await {
-            Ok(it) => {
-                tx.send((it, rt)).await.unwrap();
-            }
-            Err(err) => loop {
-                // Keep spamming till we get it..
-                if let Ok(it) = rt.download_image(&client_c).await {
+        let mut attempts = 0;
+        loop {
+            let permit = budget.acquire().await;
+            let t1 = Instant::now();
+            let res = rt.download_image(&client_c).await;
+            drop(permit);
+
+            match res {
+                Ok(it) => {
+                    let m = metrics();
+                    m.tile_latency.observe(t1.elapsed().as_secs_f64());
+                    m.tiles_fetched.inc();
+                    m.bytes_downloaded.inc_by(it.len() as u64);
+                    progress::emit(ProgressEvent::TileDownloaded {
+                        x: rt.x,
+                        y: rt.y,
+                        bytes: it.len(),
+                    });
                     tx.send((it, rt)).await.unwrap();
                     break;
                 }
-                error!("{}", err);
-            },
+                Err(err) => {
+                    // Keep spamming till we get it.. or, the budget says to stop.
+                    error!("{}", err);
+                    attempts += 1;
+                    let gave_up = budget.retries.is_some_and(|retries| attempts > retries);
+                    metrics().tiles_failed.inc();
+                    if !gave_up {
+                        metrics().tiles_retried.inc();
+                    }
+                    progress::emit(ProgressEvent::TileFailed {
+                        x: rt.x,
+                        y: rt.y,
+                        attempt: attempts,
+                        error: err.to_string(),
+                        gave_up,
+                    });
+                    if budget.retries.is_some() {
+                        if gave_up {
+                            error!(
+                                "Giving up on x{}, y{} after {attempts} attempts",
+                                rt.x, rt.y
+                            );
+                            break;
+                        }
+                        tokio::time::sleep(Duration::from_millis(500 * attempts as u64)).await;
+                    }
+                }
+            }
         }
     });
 
@@ -41,25 +141,38 @@ pub async fn tokio_tile_fetcher(
     Ok(())
 }
 
-#[async_recursion]
 pub async fn fetch_full_disc(
     client: &Client,
+    source: &TileSource,
     hwdt: HimawariDatetime,
     tx: Sender<(Bytes, RemoteTile)>,
+) -> Result<Arc<Mutex<Vec<JoinHandle<()>>>>> {
+    fetch_full_disc_with(client, source, hwdt, tx, FetchBudget::unlimited()).await
+}
+
+/// [`fetch_full_disc`], but within a [`FetchBudget`].
+pub async fn fetch_full_disc_with(
+    client: &Client,
+    source: &TileSource,
+    hwdt: HimawariDatetime,
+    tx: Sender<(Bytes, RemoteTile)>,
+    budget: FetchBudget,
 ) -> Result<Arc<Mutex<Vec<JoinHandle<()>>>>> {
     use futures::future::join_all;
     let handles = Arc::new(Mutex::new(Vec::new()));
 
+    // Row by row, so that rows complete (and can be streamed out) roughly in order.
     let fetch_tasks = (0..ROWMAX)
-        .flat_map(|x| (0..COLMAX).map(move |y| (x, y)))
+        .flat_map(|y| (0..COLMAX).map(move |x| (x, y)))
         .map(|(x, y)| {
             let client = client.clone();
             let tx = tx.clone();
             let handles = handles.clone();
+            let budget = budget.clone();
             async move {
-                let url = hwdt.get_url(x, y).unwrap();
+                let url = source.tile_url(&hwdt, x, y).unwrap();
                 let rt = RemoteTile::new(x, y, url).await;
-                tokio_tile_fetcher(rt, &client, &handles, tx).await
+                tokio_tile_fetcher(rt, &client, &handles, tx, budget).await
             }
         })
         .collect::<Vec<_>>();
@@ -68,9 +181,307 @@ pub async fn fetch_full_disc(
 
     Ok(handles)
 }
+
+/// Fetch every tile of a disc, and wait for them all, rather than streaming them as they arrive.
+/// Handy when the tiles are wanted more than once, see [`replay`]. Tiles the budget gave up on are
+/// simply missing.
+pub async fn fetch_tiles(
+    client: &Client,
+    source: &TileSource,
+    hwdt: HimawariDatetime,
+    budget: FetchBudget,
+) -> Result<Vec<(Bytes, RemoteTile)>> {
+    let (tx, mut rx) = tokio::sync::mpsc::channel(400);
+    let handles = fetch_full_disc_with(client, source, hwdt, tx, budget).await?;
+    let handles: Vec<_> = handles
+        .lock()
+        .expect("Unable to lock joinhandles")
+        .drain(..)
+        .collect();
+    for h in handles {
+        h.await?;
+    }
+
+    let mut tiles = vec![];
+    while let Some(t) = rx.recv().await {
+        tiles.push(t);
+    }
+    Ok(tiles)
+}
+
+/// Feed already fetched tiles back through a channel, as [`crate::cvutils::assemble_full_disc`] expects.
+pub fn replay(tiles: Vec<(Bytes, RemoteTile)>) -> Receiver<(Bytes, RemoteTile)> {
+    let (tx, rx) = tokio::sync::mpsc::channel(tiles.len().max(1));
+    for t in tiles {
+        tx.try_send(t).expect("channel is sized to fit every tile");
+    }
+    rx
+}
+
+/// Marks a frame in a [`TileStore`] as only cached by a [`TileFallback`].
+const CACHED: &str = ".cached";
+
+/// NICT's "No Image" tile is flat, so compresses to next to nothing, anything bigger than this
+/// isn't worth decoding to check.
+const PLACEHOLDER_MAX_BYTES: usize = 32 * 1024;
+
+/// Is this NICT's grey "No Image" tile? Served (with a 200) in place of tiles they don't have, it's
+/// almost all one flat grey, unlike anything of the earth (or the black around it).
+pub fn is_placeholder(bytes: &[u8]) -> bool {
+    if bytes.len() > PLACEHOLDER_MAX_BYTES {
+        return false;
+    }
+    let Ok(img) = image::load_from_memory(bytes) else {
+        return false;
+    };
+    let img = img.to_rgb8();
+    let mut counts: HashMap<[u8; 3], usize> = HashMap::new();
+    for p in img.pixels() {
+        *counts.entry(p.0).or_default() += 1;
+    }
+    let Some(([r, g, b], n)) = counts.into_iter().max_by_key(|(_, n)| *n) else {
+        return false;
+    };
+    let grey = r.max(g).max(b) - r.min(g).min(b) <= 2 && (48..=224).contains(&r);
+    grey && n * 10 >= img.pixels().len() * 9
+}
+
+/// Raw tiles on disk, laid out as `{root}/YYYY/MM/DD/HHMM/{x}_{y}.png`
+#[derive(Debug, Clone)]
+pub struct TileStore {
+    pub root: PathBuf,
+}
+
+impl TileStore {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    /// The `tilesdir` from the user's config.yml, or `tiles` in their `backup` dir.
+    pub fn from_config(uc: &Config) -> Self {
+        match &uc.tilesdir {
+            Some(p) => Self::new(p),
+            None => Self::new(Path::new(&uc.backup).join("tiles")),
+        }
+    }
+
+    /// Where all the tiles of a single disc live.
+    pub fn frame_dir(&self, hwdt: &HimawariDatetime) -> PathBuf {
+        self.root
+            .join(format!("{}", hwdt.year()))
+            .join(format!("{:02}", hwdt.month()))
+            .join(format!("{:02}", hwdt.day()))
+            .join(format!("{:02}{:02}", hwdt.hour(), hwdt.minute()))
+    }
+
+    pub fn path_for(&self, hwdt: &HimawariDatetime, x: u32, y: u32) -> PathBuf {
+        self.frame_dir(hwdt).join(format!("{x}_{y}.png"))
+    }
+
+    pub fn save(&self, hwdt: &HimawariDatetime, rt: &RemoteTile, bytes: &Bytes) -> Result<()> {
+        std::fs::create_dir_all(self.frame_dir(hwdt))?;
+        let (tmp, mut fd) = AtomicFile::create(self.path_for(hwdt, rt.x, rt.y))?;
+        fd.write_all(bytes)?;
+        drop(fd);
+        tmp.commit()
+    }
+
+    pub fn load(&self, hwdt: &HimawariDatetime, x: u32, y: u32) -> Option<Bytes> {
+        std::fs::read(self.path_for(hwdt, x, y))
+            .ok()
+            .map(Bytes::from)
+    }
+
+    /// Remove every frame from before `hwdt`, and any directories that leaves empty.
+    pub fn remove_before(&self, hwdt: &HimawariDatetime) -> Result<()> {
+        self.remove_frames_before(hwdt, |_| true)
+    }
+
+    /// [`TileStore::remove_before`], but only the frames [`TileStore::mark_cached`], so anything
+    /// backfilled alongside them is left be.
+    pub fn remove_cached_before(&self, hwdt: &HimawariDatetime) -> Result<()> {
+        self.remove_frames_before(hwdt, |frame| frame.join(CACHED).is_file())
+    }
+
+    /// Flags a frame as only being kept for a [`TileFallback`].
+    pub fn mark_cached(&self, hwdt: &HimawariDatetime) -> Result<()> {
+        let marker = self.frame_dir(hwdt).join(CACHED);
+        if !marker.is_file() {
+            std::fs::create_dir_all(self.frame_dir(hwdt))?;
+            File::create(marker)?;
+        }
+        Ok(())
+    }
+
+    /// The frame's wanted for good, i.e it's been backfilled.
+    pub fn unmark_cached(&self, hwdt: &HimawariDatetime) {
+        _ = std::fs::remove_file(self.frame_dir(hwdt).join(CACHED));
+    }
+
+    fn remove_frames_before(
+        &self,
+        hwdt: &HimawariDatetime,
+        doomed: impl Fn(&Path) -> bool,
+    ) -> Result<()> {
+        if !self.root.is_dir() {
+            return Ok(());
+        }
+        for year in subdirs(&self.root)? {
+            for month in subdirs(&year)? {
+                for day in subdirs(&month)? {
+                    for frame in subdirs(&day)? {
+                        // i.e tiles/2022/09/21/0010
+                        let name = [&year, &month, &day, &frame]
+                            .map(|p| p.file_name().unwrap_or_default().to_string_lossy())
+                            .concat();
+                        match name.parse::<HimawariDatetime>() {
+                            Ok(t) if t < *hwdt && doomed(&frame) => {
+                                std::fs::remove_dir_all(&frame)?
+                            }
+                            _ => {}
+                        }
+                    }
+                    _ = std::fs::remove_dir(&day);
+                }
+                _ = std::fs::remove_dir(&month);
+            }
+            _ = std::fs::remove_dir(&year);
+        }
+        Ok(())
+    }
+
+    /// Are all 400 tiles of this disc on disk?
+    pub fn has_frame(&self, hwdt: &HimawariDatetime) -> bool {
+        (0..ROWMAX)
+            .flat_map(|y| (0..COLMAX).map(move |x| (x, y)))
+            .all(|(x, y)| self.path_for(hwdt, x, y).is_file())
+    }
+}
+
+/// How to cope with tiles that never arrive, lives under `fallback:` in the config.yml
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FallbackSettings {
+    /// Give up on a tile after this many retries, rather than trying forever.
+    pub retries: Option<u32>,
+    /// How many of a disc's tiles may be missing (and so, filled in from earlier frames) before
+    /// the whole disc is rejected.
+    pub max_substituted: usize,
+    /// How many frames (of 10 minutes) back to look for a stand-in.
+    pub lookback: u32,
+    /// Where tiles are kept to fall back on, defaults to your `tilesdir` (so backfilled tiles can
+    /// stand in too).
+    pub dir: Option<String>,
+}
+
+impl Default for FallbackSettings {
+    fn default() -> Self {
+        Self {
+            retries: Some(10),
+            max_substituted: 20,
+            lookback: 6,
+            dir: None,
+        }
+    }
+}
+
+/// A disc with more tiles missing than [`FallbackSettings::max_substituted`] allows, so it was
+/// rejected rather than saved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TooManyMissing {
+    pub hwdt: HimawariDatetime,
+    pub missing: usize,
+    pub allowed: usize,
+}
+
+impl std::fmt::Display for TooManyMissing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} tiles are missing from {}, more than the {} allowed",
+            self.missing, self.hwdt, self.allowed
+        )
+    }
+}
+
+impl std::error::Error for TooManyMissing {}
+
+/// Stands in for tiles of `hwdt` that never arrived (or were only NICT's placeholder), with the
+/// same tile from the most recent earlier frame we have. Every tile that does arrive is kept for
+/// next time.
+#[derive(Debug, Clone)]
+pub struct TileFallback {
+    pub store: TileStore,
+    pub hwdt: HimawariDatetime,
+    pub lookback: u32,
+    pub max_substituted: usize,
+}
+
+impl TileFallback {
+    pub fn from_config(uc: &Config, hwdt: HimawariDatetime) -> Self {
+        let settings = &uc.fallback;
+        let store = match &settings.dir {
+            Some(p) => TileStore::new(p),
+            None => TileStore::from_config(uc),
+        };
+        Self {
+            store,
+            hwdt,
+            lookback: settings.lookback,
+            max_substituted: settings.max_substituted,
+        }
+    }
+
+    /// Hold onto a tile that did arrive, failures are only logged as the disc itself is fine.
+    pub fn keep(&self, rt: &RemoteTile, bytes: &Bytes) {
+        if self.store.path_for(&self.hwdt, rt.x, rt.y).is_file() {
+            return;
+        }
+        if let Err(e) = self
+            .store
+            .mark_cached(&self.hwdt)
+            .and_then(|_| self.store.save(&self.hwdt, rt, bytes))
+        {
+            warn!("Unable to cache x{}, y{}: {e}", rt.x, rt.y);
+        }
+    }
+
+    /// The tile at `x`, `y` from the most recent earlier frame that has it, and that frame.
+    pub fn find(&self, x: u32, y: u32) -> Option<(Bytes, RemoteTile, HimawariDatetime)> {
+        (1..=self.lookback as i64)
+            .map(|n| self.hwdt - chrono::Duration::minutes(10 * n))
+            .find_map(|t| {
+                let bytes = self.store.load(&t, x, y).filter(|b| !is_placeholder(b))?;
+                let url = Url::from_file_path(self.store.path_for(&t, x, y)).ok()?;
+                Some((bytes, RemoteTile { x, y, url }, t))
+            })
+    }
+
+    /// Drop anything we cached that's too old to ever be looked at again.
+    pub fn tidy(&self) {
+        let oldest = self.hwdt - chrono::Duration::minutes(10 * self.lookback as i64);
+        if let Err(e) = self.store.remove_cached_before(&oldest) {
+            warn!("Unable to tidy {}: {e}", self.store.root.display());
+        }
+    }
+}
+
+fn subdirs(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let p = entry?.path();
+        if p.is_dir() {
+            dirs.push(p);
+        }
+    }
+    Ok(dirs)
+}
+
 /// Creates an Image from [`Bytes`]!
-pub(crate) fn img_from(b: Bytes) -> DynamicImage {
-    image::load_from_memory(&b).unwrap() //TODO: fix this, error handling.
+pub(crate) fn img_from(b: Bytes) -> Result<DynamicImage> {
+    Ok(image::load_from_memory(&b)?)
 }
 
 /// Hold the data for a single tile, prior to fetching it from the dataset
@@ -93,12 +504,20 @@ impl RemoteTile {
             }
         }
     }
-    /// Downloads the tile to the specified path
-    pub async fn download_image(&self, client: &Client) -> Result<Bytes, reqwest::Error> {
+    /// Downloads the tile, `file://` urls are read straight off the disk.
+    pub async fn download_image(&self, client: &Client) -> Result<Bytes> {
+        if self.url.scheme() == "file" {
+            let p = self
+                .url
+                .to_file_path()
+                .map_err(|_| anyhow::anyhow!("Invalid file url: {}", self.url))?;
+            return Ok(Bytes::from(tokio::fs::read(p).await?));
+        }
+
         let url = self.url.clone();
         let resp = client.get(url).send().await?.error_for_status()?;
 
-        let bytes = resp.bytes().await.unwrap();
+        let bytes = resp.bytes().await?;
 
         Ok(bytes)
     }
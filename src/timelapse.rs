@@ -0,0 +1,289 @@
+//! Numbered frames for a time-lapse, with extra frames made up in between Himawari's 10 minute
+//! ones so it doesn't look so choppy. Stitch them together with something like
+//! `ffmpeg -framerate 30 -i frame_%05d.png timelapse.mp4`.
+
+use crate::archive::Archive;
+use crate::cli::Cli;
+use crate::himawaridt::{HimawariDatetime, HimawariRange};
+use crate::update::fetch_and_assemble;
+use crate::user_config::Config;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use image::imageops::FilterType;
+use image::{GrayImage, Rgb, RgbImage};
+use log::{debug, info};
+use reqwest::Client;
+use std::path::{Path, PathBuf};
+
+/// Motion is estimated on a copy of the discs shrunk to (at most) this wide, as it's slow.
+const ESTIMATE_PX: u32 = 1024;
+/// Size of the blocks matched between frames, at the shrunk size.
+const BLOCK_PX: u32 = 8;
+/// How far (in px, at the shrunk size) a block may have moved between frames.
+const SEARCH_PX: i32 = 8;
+
+/// How the frames in between are made.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Interpolation {
+    /// No extra frames.
+    None,
+    /// Blend between each pair of frames.
+    Crossfade,
+    /// Move blocks of each frame towards where they turn up in the next, much smoother clouds.
+    Motion,
+}
+
+/// What to make a time-lapse of, and how.
+#[derive(Debug, Clone)]
+pub struct TimelapseOpts {
+    pub range: HimawariRange,
+    /// Where the numbered frames go.
+    pub out: PathBuf,
+    pub interpolation: Interpolation,
+    /// How many frames to make up between each pair of real ones.
+    pub between: u32,
+    /// Width (and height) in px of the frames, no bigger than the first disc, which is the size
+    /// they are if unset.
+    pub size: Option<u32>,
+}
+
+/// A made up frame `t` (0.0 - 1.0) of the way from `a` to `b`.
+pub fn crossfade(a: &RgbImage, b: &RgbImage, t: f32) -> RgbImage {
+    RgbImage::from_fn(a.width(), a.height(), |x, y| {
+        blend(a.get_pixel(x, y), b.get_pixel(x, y), t)
+    })
+}
+
+fn blend(a: &Rgb<u8>, b: &Rgb<u8>, t: f32) -> Rgb<u8> {
+    Rgb([0, 1, 2].map(|c| (a[c] as f32 * (1.0 - t) + b[c] as f32 * t).round() as u8))
+}
+
+/// Where each block of `a` moved to in `b`, in px of `a`, see [`motion_interpolate`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct MotionField {
+    /// Size of each block, in px of the full sized frames.
+    block: f32,
+    cols: u32,
+    vectors: Vec<(f32, f32)>,
+}
+
+impl MotionField {
+    /// Block matching, each block of `a` is looked for in `b` within [`SEARCH_PX`], whichever
+    /// offset differs least (staying put if that's as good) is where it went.
+    pub fn estimate(a: &RgbImage, b: &RgbImage) -> Self {
+        let (sa, scale) = shrink(a);
+        let (sb, _) = shrink(b);
+        let cols = sa.width().div_ceil(BLOCK_PX);
+        let rows = sa.height().div_ceil(BLOCK_PX);
+
+        let mut vectors = Vec::with_capacity((cols * rows) as usize);
+        for by in 0..rows {
+            for bx in 0..cols {
+                let (x0, y0) = ((bx * BLOCK_PX) as i32, (by * BLOCK_PX) as i32);
+                let mut best = (sad(&sa, &sb, x0, y0, 0, 0), (0, 0));
+                for dy in -SEARCH_PX..=SEARCH_PX {
+                    for dx in -SEARCH_PX..=SEARCH_PX {
+                        let d = sad(&sa, &sb, x0, y0, dx, dy);
+                        if d < best.0 {
+                            best = (d, (dx, dy));
+                        }
+                    }
+                }
+                let (dx, dy) = best.1;
+                vectors.push((dx as f32 * scale, dy as f32 * scale));
+            }
+        }
+
+        Self {
+            block: BLOCK_PX as f32 * scale,
+            cols,
+            vectors,
+        }
+    }
+
+    fn at(&self, x: u32, y: u32) -> (f32, f32) {
+        let bx = (x as f32 / self.block) as u32;
+        let by = (y as f32 / self.block) as u32;
+        self.vectors
+            .get((by * self.cols + bx.min(self.cols - 1)) as usize)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// Grey, and no wider than [`ESTIMATE_PX`], along with how much smaller it got.
+fn shrink(img: &RgbImage) -> (GrayImage, f32) {
+    let grey = image::imageops::grayscale(img);
+    if img.width() <= ESTIMATE_PX {
+        return (grey, 1.0);
+    }
+    let scale = img.width() as f32 / ESTIMATE_PX as f32;
+    let h = (img.height() as f32 / scale).round() as u32;
+    (
+        image::imageops::resize(&grey, ESTIMATE_PX, h, FilterType::Triangle),
+        scale,
+    )
+}
+
+/// Sum of absolute differences between the block at `x0`, `y0` of `a` and the same block moved by
+/// `dx`, `dy` in `b`, `u64::MAX` if that's off the edge.
+fn sad(a: &GrayImage, b: &GrayImage, x0: i32, y0: i32, dx: i32, dy: i32) -> u64 {
+    let (w, h) = (a.width() as i32, a.height() as i32);
+    let (x1, y1) = ((x0 + BLOCK_PX as i32).min(w), (y0 + BLOCK_PX as i32).min(h));
+    if x0 + dx < 0 || y0 + dy < 0 || x1 + dx > w || y1 + dy > h {
+        return u64::MAX;
+    }
+    let mut total = 0;
+    for y in y0..y1 {
+        for x in x0..x1 {
+            let pa = a.get_pixel(x as u32, y as u32)[0];
+            let pb = b.get_pixel((x + dx) as u32, (y + dy) as u32)[0];
+            total += pa.abs_diff(pb) as u64;
+        }
+    }
+    total
+}
+
+/// A made up frame `t` (0.0 - 1.0) of the way from `a` to `b`, with everything moved `t` of the
+/// way along `field`.
+pub fn motion_interpolate(a: &RgbImage, b: &RgbImage, field: &MotionField, t: f32) -> RgbImage {
+    let (w, h) = (a.width() as f32, a.height() as f32);
+    let sample = |img: &RgbImage, x: f32, y: f32| {
+        *img.get_pixel(
+            x.round().clamp(0.0, w - 1.0) as u32,
+            y.round().clamp(0.0, h - 1.0) as u32,
+        )
+    };
+    RgbImage::from_fn(a.width(), a.height(), |x, y| {
+        let (vx, vy) = field.at(x, y);
+        let (xf, yf) = (x as f32, y as f32);
+        let from_a = sample(a, xf - t * vx, yf - t * vy);
+        let from_b = sample(b, xf + (1.0 - t) * vx, yf + (1.0 - t) * vy);
+        blend(&from_a, &from_b, t)
+    })
+}
+
+/// The `between` frames that go between `a` and `b`.
+pub fn interpolate(
+    a: &RgbImage,
+    b: &RgbImage,
+    between: u32,
+    interpolation: Interpolation,
+) -> Vec<RgbImage> {
+    let ts = (1..=between).map(|i| i as f32 / (between + 1) as f32);
+    match interpolation {
+        Interpolation::None => vec![],
+        Interpolation::Crossfade => ts.map(|t| crossfade(a, b, t)).collect(),
+        Interpolation::Motion => {
+            let field = MotionField::estimate(a, b);
+            ts.map(|t| motion_interpolate(a, b, &field, t)).collect()
+        }
+    }
+}
+
+/// The disc for `hwdt`, from the archive, `completed` or `backup` if we have it, otherwise it's
+/// fetched.
+async fn disc_for(
+    client: &Client,
+    uc: &Config,
+    cli: &Cli,
+    hwdt: HimawariDatetime,
+) -> Result<PathBuf> {
+    let archived = Archive::from_config(uc).local_disc(uc, hwdt)?;
+    match archived {
+        Some(p) => Ok(p),
+        None => Ok(fetch_and_assemble(client, uc, cli, hwdt).await?.path),
+    }
+}
+
+fn load(p: &Path, size: u32) -> Result<RgbImage> {
+    let img = image::open(p)?;
+    Ok(match img.width() == size && img.height() == size {
+        true => img.to_rgb8(),
+        false => img.resize_exact(size, size, FilterType::Lanczos3).to_rgb8(),
+    })
+}
+
+/// Write `frame_00000.png` etc to `opts.out`, returning their paths.
+pub async fn timelapse(
+    client: &Client,
+    uc: &Config,
+    cli: &Cli,
+    opts: &TimelapseOpts,
+) -> Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(&opts.out)?;
+    let mut written = vec![];
+    let mut write = |img: &RgbImage| -> Result<()> {
+        let p = opts.out.join(format!("frame_{:05}.png", written.len()));
+        img.save(&p)?;
+        debug!("Wrote {}", p.display());
+        written.push(p);
+        Ok(())
+    };
+
+    info!("Making a time-lapse of {} frames", opts.range.len());
+    let mut previous: Option<RgbImage> = None;
+    let mut frame_px = None;
+    for hwdt in opts.range {
+        let p = disc_for(client, uc, cli, hwdt).await?;
+        // Every frame's as big as the first disc, there's nothing to gain from scaling them up.
+        let size = match frame_px {
+            Some(size) => size,
+            None => {
+                let (w, _) = image::image_dimensions(&p)?;
+                *frame_px.insert(opts.size.map_or(w, |s| s.min(w)))
+            }
+        };
+        let img = load(&p, size)?;
+        if let Some(prev) = &previous {
+            for frame in interpolate(prev, &img, opts.between, opts.interpolation) {
+                write(&frame)?;
+            }
+        }
+        write(&img)?;
+        previous = Some(img);
+    }
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: Rgb<u8> = Rgb([255, 255, 255]);
+    const BLACK: Rgb<u8> = Rgb([0, 0, 0]);
+
+    /// A white square 16px across at `x`, 16.
+    fn square_at(x0: u32) -> RgbImage {
+        RgbImage::from_fn(64, 64, |x, y| {
+            match (x0..x0 + 16).contains(&x) && (16..32).contains(&y) {
+                true => WHITE,
+                false => BLACK,
+            }
+        })
+    }
+
+    #[test]
+    fn crossfades() {
+        let frames = interpolate(&square_at(16), &square_at(24), 3, Interpolation::Crossfade);
+        assert_eq!(frames.len(), 3);
+        // Half way through the square's left edge is half there.
+        assert_eq!(frames[1].get_pixel(21, 20), &Rgb([128, 128, 128]));
+        assert!(interpolate(&square_at(16), &square_at(24), 3, Interpolation::None).is_empty());
+    }
+
+    #[test]
+    fn motion_moves_things_half_way() {
+        let (a, b) = (square_at(16), square_at(24));
+        let field = MotionField::estimate(&a, &b);
+        assert_eq!(field.at(17, 17), (8.0, 0.0));
+        assert_eq!(field.at(2, 2), (0.0, 0.0));
+
+        let mid = motion_interpolate(&a, &b, &field, 0.5);
+        assert_eq!(mid.get_pixel(21, 20), &WHITE);
+        assert_eq!(mid.get_pixel(12, 20), &BLACK);
+        assert_eq!(mid.get_pixel(50, 50), &BLACK);
+    }
+}
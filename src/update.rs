@@ -0,0 +1,133 @@
+//! A single go of what rustwari does by default: get the latest disc, set it, tidy up.
+
+use crate::archive::Archive;
+use crate::cli::Cli;
+use crate::cvutils::assemble_full_disc;
+use crate::fileutils::move_completed_to_backup;
+use crate::himawaridt::HimawariDatetime;
+use crate::hooks::{HookEnv, Stage};
+use crate::outputs::{shrunk_disc, write_outputs};
+use crate::palette::export_for;
+use crate::peer::Peer;
+use crate::retention::prune;
+use crate::sources::TileSource;
+use crate::tiles::{fetch_full_disc_with, FetchBudget, TileFallback};
+use crate::user_config::Config;
+use crate::wallpaperutils::{FullDisc, WallpaperBackend};
+
+use anyhow::Result;
+use log::{debug, warn};
+use reqwest::Client;
+use tokio::sync::mpsc;
+
+/// Get the disc for `hwdt` (from disk if we have it already, or a peer if one's configured and
+/// it's the latest), set it with `backend`, back it up if asked to and apply the retention
+/// policies. The configured hooks are run along the way.
+pub async fn update_wallpaper(
+    client: &Client,
+    uc: &Config,
+    cli: &Cli,
+    hwdt: HimawariDatetime,
+    backend: &dyn WallpaperBackend,
+) -> Result<FullDisc> {
+    let env = HookEnv::new(hwdt, backend.name());
+    uc.hooks.run(Stage::PreFetch, &env).await?;
+
+    // At night the same earlier frame is chosen run after run, so it's only fetched the once.
+    let local = Archive::from_config(uc).local_disc(uc, hwdt)?;
+    let reused = local.is_some();
+    let fulldisc = match local {
+        Some(p) => {
+            debug!("Already have {hwdt}: {}", p.display());
+            FullDisc::new(&p, uc.output)?
+        }
+        None => match from_peer(client, uc, hwdt).await? {
+            Some(fulldisc) => fulldisc,
+            None => fetch_and_assemble(client, uc, cli, hwdt).await?,
+        },
+    };
+
+    // Decoded once, for both the palette and extra outputs.
+    let shrunk = shrunk_disc(uc, &fulldisc.path).await;
+    if let Some(shrunk) = &shrunk {
+        export_for(uc, &fulldisc.path, &shrunk.img);
+    }
+
+    // A peer's disc may not be from `hwdt`.
+    let saved = uc.filename.parse(&fulldisc.path).map_or(hwdt, |n| n.hwdt);
+    let env = env.with_disc(&fulldisc, saved);
+    uc.hooks.run(Stage::PostSave, &env).await?;
+
+    fulldisc.set_with(backend)?;
+    if let Some(shrunk) = shrunk {
+        write_outputs(uc, shrunk).await;
+    }
+    uc.hooks.run(Stage::PostSet, &env).await?;
+
+    // A disc we already had was backed up (if it was going to be) when it was first fetched.
+    if cli.backup && !reused {
+        move_completed_to_backup(fulldisc.path.clone(), uc)?;
+    }
+
+    prune(uc, false)?;
+    Ok(fulldisc)
+}
+
+/// The disc from the configured peer, if there is one and `hwdt` is the latest.
+async fn from_peer(
+    client: &Client,
+    uc: &Config,
+    hwdt: HimawariDatetime,
+) -> Result<Option<FullDisc>> {
+    let peer = match Peer::from_config(uc)? {
+        Some(peer) if hwdt == HimawariDatetime::closest_to_now() => peer,
+        _ => return Ok(None),
+    };
+    match peer.fetch_latest(client, uc).await {
+        Ok(fulldisc) => Ok(Some(fulldisc)),
+        Err(e) => {
+            warn!(
+                "Unable to get the latest disc from {}: {e}, fetching tiles instead.",
+                peer.base_url
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// Fetch the tiles for `hwdt` from the configured source and assemble them.
+pub async fn fetch_and_assemble(
+    client: &Client,
+    uc: &Config,
+    cli: &Cli,
+    hwdt: HimawariDatetime,
+) -> Result<FullDisc> {
+    let (tx, rx) = mpsc::channel(400);
+
+    let mut budget = FetchBudget::unlimited();
+    budget.retries = uc.fallback.retries;
+    let handles =
+        fetch_full_disc_with(client, &TileSource::from_config(uc)?, hwdt, tx, budget).await?;
+
+    let handles: Vec<_> = handles
+        .lock()
+        .expect("Unable to lock joinhandles")
+        .drain(..)
+        .collect();
+    let fetched = async {
+        for h in handles {
+            h.await?;
+        }
+        Ok::<_, anyhow::Error>(())
+    };
+
+    // Tiles are assembled as they land, rather than once they've all arrived.
+    // NOTE: when cli.resize is set the tiles are downsampled as they're assembled.
+    let fallback = TileFallback::from_config(uc, hwdt);
+    let (fulldisc, fetched) = tokio::join!(
+        assemble_full_disc(hwdt, uc, cli, rx, Some(&fallback)),
+        fetched
+    );
+    fetched?;
+    fulldisc
+}
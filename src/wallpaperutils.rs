@@ -1,18 +1,108 @@
-use crate::cvutils::get_dims;
+use crate::cvutils::{get_dims, write_raw, OutputSettings};
+use crate::fileutils::AtomicFile;
+use crate::metrics::metrics;
+use crate::progress::{self, ProgressEvent};
 
-use anyhow::Error;
-use anyhow::Result;
+use anyhow::{anyhow, bail, Error, Result};
 use image::imageops::FilterType;
-use image::ImageFormat;
 use log::debug;
 use std::fs;
-use std::path::PathBuf;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
 
 /// Sets the background for any non pop!_os OS.
 pub fn set_from_path<P: Into<String>>(p: P) {
     wallpaper::set_from_path(&p.into()).expect("Unable to set wallpaper.");
 }
 
+/// Whatever actually sets the wallpaper, swappable so that tests (and unusual desktops) needn't
+/// touch the real thing.
+pub trait WallpaperBackend {
+    fn set(&self, p: &Path) -> Result<()>;
+
+    /// What hooks are told set the wallpaper, see [`crate::hooks`].
+    fn name(&self) -> &str {
+        "custom"
+    }
+
+    /// What setting `p` would do, for `--dry-run`.
+    fn describe(&self, p: &Path) -> String {
+        format!("{} {}", self.name(), p.display())
+    }
+}
+
+/// The `wallpaper` crate, which covers most desktops.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct System;
+
+impl WallpaperBackend for System {
+    fn set(&self, p: &Path) -> Result<()> {
+        wallpaper::set_from_path(&p.display().to_string())
+            .map_err(|e| anyhow!("Unable to set wallpaper: {e}"))
+    }
+
+    fn name(&self) -> &str {
+        "system"
+    }
+
+    fn describe(&self, p: &Path) -> String {
+        format!("wallpaper::set_from_path(\"{}\")", p.display())
+    }
+}
+
+/// hyprland's hyprpaper (wayland only)
+#[cfg(feature = "hypr")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Hyprpaper;
+
+#[cfg(feature = "hypr")]
+impl WallpaperBackend for Hyprpaper {
+    fn set(&self, p: &Path) -> Result<()> {
+        hyprctl(&["unload", "all"])?;
+        log::debug!("Preloading: {:?}", p.display());
+        hyprctl(&["preload", &p.display().to_string()])?;
+        hyprctl(&["wallpaper", &format!("DP-1,contain:{}", p.display())])
+    }
+
+    fn name(&self) -> &str {
+        "hyprpaper"
+    }
+
+    fn describe(&self, p: &Path) -> String {
+        format!(
+            "hyprctl hyprpaper unload all && hyprctl hyprpaper preload {0} && hyprctl hyprpaper wallpaper DP-1,contain:{0}",
+            p.display()
+        )
+    }
+}
+
+/// Run `hyprctl hyprpaper <args>`, it failing is an error, with whatever it said on stderr.
+#[cfg(feature = "hypr")]
+fn hyprctl(args: &[&str]) -> Result<()> {
+    let out = std::process::Command::new("hyprctl")
+        .arg("hyprpaper")
+        .args(args)
+        .output()
+        .map_err(|e| anyhow!("Failed to run hyprctl: {e}"))?;
+    if !out.status.success() {
+        bail!(
+            "hyprctl hyprpaper {} failed with: {}",
+            args[0],
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// The backend this build of rustwari uses by default.
+pub fn default_backend() -> Box<dyn WallpaperBackend> {
+    #[cfg(feature = "hypr")]
+    return Box::new(Hyprpaper);
+
+    #[cfg(not(feature = "hypr"))]
+    Box::new(System)
+}
+
 #[derive(Debug)]
 /// Representing a full, 121MP [`image`]
 pub struct FullDisc {
@@ -22,89 +112,80 @@ pub struct FullDisc {
     pub(crate) height: u32,
     pub(crate) width: u32,
     pub(crate) size: u64, // NOTE: a daytime image is almos 150mb, the nightime images can be as little as 20mb.
+    /// How the image at `path` was encoded.
+    pub(crate) output: OutputSettings,
 }
 
 impl FullDisc {
-    pub(crate) fn new(p: &PathBuf) -> Result<Self> {
+    pub(crate) fn new(p: &Path, output: OutputSettings) -> Result<Self> {
         let (width, height) = get_dims(p)?;
-        let size = std::fs::metadata(p.clone())?.len();
+        let size = std::fs::metadata(p)?.len();
 
         Ok(Self {
             path: fs::canonicalize(p)?,
             height,
             width,
             size,
+            output,
         })
     }
     /// Sets the current wallpaper to whatever's stored in the path field.
     pub fn set_this(&self) -> Result<(), Error> {
-        //TODO: fix these unwraps..
-        if self.path.metadata().ok().unwrap().len() > 0 {
-            _ = set_from_path::<String>(self.path.to_str().unwrap().into());
-            Ok(())
-        } else {
-            panic!("File failed to concatenate/parse or something, check your directories are set correctly etc.")
-        }
+        self.set_with(&System)
     }
 
     /// Sets the wallpaper wit hyperland's hyprpaper (wayland only)
     #[cfg(feature = "hypr")]
     pub fn set_with_hyprpaper(&self) {
-        // Unload
-        let hyprctrl_unload = std::process::Command::new("hyprctl")
-            .arg("hyprpaper")
-            .arg("unload")
-            .arg("all")
-            .output()
-            .expect("Failed to execute command");
-
-        if !hyprctrl_unload.status.success() {
-            eprintln!(
-                "hyprctrl unload failed with: {}",
-                String::from_utf8_lossy(&hyprctrl_unload.stderr)
-            );
-        }
+        _ = Hyprpaper.set(&self.path);
+    }
 
-        // Preload
-        let hyprctrl_preload = std::process::Command::new("hyprctl")
-            .arg("hyprpaper")
-            .arg("preload")
-            .arg(format!("{}", self.path.display()))
-            .output()
-            .expect("Failed to execute command");
-        log::debug!("Preloading: {:?}", self.path.display());
-
-        if !hyprctrl_preload.status.success() {
-            eprintln!(
-                "hyprctrl preload failed with: {}",
-                String::from_utf8_lossy(&hyprctrl_preload.stderr)
+    /// Sets the wallpaper with whichever `backend` you like.
+    pub fn set_with(&self, backend: &dyn WallpaperBackend) -> Result<()> {
+        if self.path.metadata()?.len() == 0 {
+            metrics().set_failures.inc();
+            bail!(
+                "{} is empty, check your directories are set correctly etc.",
+                self.path.display()
             );
         }
-
-        // Set
-        let hyprctrl_set = std::process::Command::new("hyprctl")
-            .arg("hyprpaper")
-            .arg("wallpaper")
-            .arg(format!("DP-1,contain:{}", self.path.display()))
-            .output()
-            .expect("Failed to execute command");
-
-        if !hyprctrl_set.status.success() {
-            eprintln!(
-                "hyprctrl wallpaper failed with: {}",
-                String::from_utf8_lossy(&hyprctrl_set.stderr)
-            );
+        if let Err(e) = backend.set(&self.path) {
+            metrics().set_failures.inc();
+            return Err(e);
         }
+        metrics()
+            .last_success
+            .set(chrono::Utc::now().timestamp() as f64);
+        progress::emit(ProgressEvent::Set {
+            path: self.path.clone(),
+        });
+        Ok(())
     }
 
-    /// When called on [`FullDisc`] it resizes the 121MP .png to a smaller jpeg
-    /// Note: This method replaces the .png file (original) with the resized one.
+    /// When called on [`FullDisc`] it resizes the 121MP image to something smaller, re-encoding it
+    /// in the same format it was written with.
+    /// Note: This method replaces the file (original) with the resized one, once it's safely on disk.
     pub fn resize_this(&mut self, width: u32, height: u32) -> Result<()> {
         let img = image::open(&self.path)?;
         let resized = img.resize(width, height, FilterType::Lanczos3);
 
-        resized.save_with_format(&self.path, ImageFormat::Jpeg)?;
+        let (tmp, fd) = AtomicFile::create(&self.path)?;
+        let mut fd = BufWriter::new(fd);
+        let pixels = self.output.pixels_of(&resized);
+        write_raw(
+            &mut fd,
+            &pixels,
+            resized.width(),
+            resized.height(),
+            &self.output,
+        )?;
+        fd.flush()?;
+        drop(fd);
+        tmp.commit()?;
+
         self.path = fs::canonicalize(&self.path)?;
+        (self.width, self.height) = get_dims(&self.path)?;
+        self.size = fs::metadata(&self.path)?.len();
         debug!("Resize, success: {}", &self.path.display());
         Ok(())
     }
@@ -118,7 +199,7 @@ mod tests {
     #[test]
     fn set_from_path_works() {
         let path = PathBuf::from("test_data/fulldisc-2022-09-21 00_10.png");
-        _ = set_from_path(path);
+        set_from_path(path.display().to_string());
     }
 
     #[ignore]
@@ -0,0 +1,313 @@
+//! Dates as people type them, for `--oneshot` and `backfill`.
+//!
+//! A time is any of:
+//! - `latest` (or `now`), the newest disc that's (very likely) been published.
+//! - `2022-09-21 00:10`, `2022-09-21T00:10:00Z`, `202209210010`, `2022-09-21` etc.
+//! - `today 06:00`, `yesterday 12:00` or just `yesterday` (midnight).
+//! - `-3h`, `-90m`, `-1d6h`, that long before now, though never newer than `latest` (which is
+//!   already a few hours behind).
+//!
+//! All UTC, unless followed by a zone, `2022-09-21 09:10 +09:00`, `yesterday 12:00 Asia/Tokyo` or
+//! `today 06:00 local` (whatever your machine's set to).
+//!
+//! A range is `<time>..<time>`, with an optional `/<step>`, i.e `yesterday..latest/1h`, leave out
+//! the end for everything up to `latest`.
+
+use crate::himawaridt::{HimawariDatetime, HimawariRange, CADENCE_MINUTES};
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{
+    DateTime, Duration, FixedOffset, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc,
+};
+use chrono_tz::Tz;
+
+/// Naive formats, beyond those [`HimawariDatetime`] already parses.
+const FORMATS: [&str; 3] = ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%MZ"];
+
+/// With the offset stuck on the end, as in ISO 8601.
+const OFFSET_FORMATS: [&str; 2] = ["%Y-%m-%dT%H:%M%:z", "%Y-%m-%dT%H:%M%z"];
+
+/// Parse a time, or range of them, see the module docs.
+pub fn parse_when(s: &str) -> Result<HimawariRange> {
+    parse_when_at(s, Utc::now())
+}
+
+/// [`parse_when`] as if it were `now`.
+pub fn parse_when_at(s: &str, now: DateTime<Utc>) -> Result<HimawariRange> {
+    let s = s.trim();
+    let Some((start, rest)) = s.split_once("..") else {
+        return Ok(HimawariRange::single(parse_time_at(s, now)?));
+    };
+
+    // Named zones have a / in them too, so it's only a step if it looks like one.
+    let (end, step) = match rest.rsplit_once('/') {
+        Some((end, step)) if parse_every(step).is_ok() => (end, parse_every(step)?),
+        _ => (rest, Duration::minutes(CADENCE_MINUTES as i64)),
+    };
+    let end = match end.trim() {
+        "" => HimawariDatetime::closest_to(now),
+        end => parse_time_at(end, now)?,
+    };
+    HimawariRange::new(parse_time_at(start, now)?, end, step)
+}
+
+/// A single time, see the module docs.
+pub fn parse_time_at(s: &str, now: DateTime<Utc>) -> Result<HimawariDatetime> {
+    let s = s.trim();
+    let lower = s.to_ascii_lowercase();
+    if lower == "latest" || lower == "now" {
+        return Ok(HimawariDatetime::closest_to(now));
+    }
+    if let Some(ago) = lower.strip_prefix('-') {
+        let then = HimawariDatetime::try_from(now - parse_duration(ago)?)?;
+        return Ok(then.min(HimawariDatetime::closest_to(now)));
+    }
+
+    if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+        return HimawariDatetime::try_from(dt.with_timezone(&Utc));
+    }
+    if let Some(dt) = OFFSET_FORMATS
+        .iter()
+        .find_map(|fmt| DateTime::parse_from_str(s, fmt).ok())
+    {
+        return HimawariDatetime::try_from(dt.with_timezone(&Utc));
+    }
+
+    // The zone, if there is one, is the last word.
+    let (rest, zone) = match s.rsplit_once(char::is_whitespace) {
+        Some((rest, last)) => match Zone::parse(last) {
+            Some(zone) => (rest.trim(), zone),
+            None => (s, Zone::Utc),
+        },
+        None => (s, Zone::Utc),
+    };
+    let naive = parse_naive(rest, zone.today(now))?;
+    HimawariDatetime::try_from(zone.to_utc(naive)?)
+}
+
+/// `yesterday 12:00`, `2022-09-21 00:10`, `2022-09-21` etc, `today` being as given.
+fn parse_naive(s: &str, today: NaiveDate) -> Result<NaiveDateTime> {
+    let (day, time) = match s.split_once(char::is_whitespace) {
+        Some((day, time)) => (day, Some(time.trim())),
+        None => (s, None),
+    };
+    let relative = match day.to_ascii_lowercase().as_str() {
+        "today" => Some(today),
+        "yesterday" => today.pred_opt(),
+        _ => None,
+    };
+    if let Some(date) = relative {
+        let time = match time {
+            Some(t) => NaiveTime::parse_from_str(t, "%H:%M")
+                .map_err(|_| anyhow!("Unable to parse the time in {s}, try HH:MM"))?,
+            None => NaiveTime::MIN,
+        };
+        return Ok(date.and_time(time));
+    }
+
+    if let Ok(hwdt) = s.parse::<HimawariDatetime>() {
+        return Ok(hwdt.datetime().naive_utc());
+    }
+    if let Some(dt) = FORMATS
+        .iter()
+        .find_map(|fmt| NaiveDateTime::parse_from_str(s, fmt).ok())
+    {
+        return Ok(dt);
+    }
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map(|d| d.and_time(NaiveTime::MIN))
+        .map_err(|_| {
+            anyhow!("Unable to parse {s}, try YYYY-MM-DD HH:MM, yesterday 12:00, -3h or latest")
+        })
+}
+
+/// Which zone a time was given in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Zone {
+    Utc,
+    Local,
+    Fixed(FixedOffset),
+    Named(Tz),
+}
+
+impl Zone {
+    /// `Z`, `UTC`, `local`, `+09:00`, `-0500` or `Asia/Tokyo`.
+    fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "z" | "utc" | "gmt" => return Some(Zone::Utc),
+            "local" => return Some(Zone::Local),
+            _ => {}
+        }
+        if s.starts_with(['+', '-']) {
+            let digits = s[1..].replace(':', "");
+            if !digits.chars().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            let (h, m) = match digits.len() {
+                2 => (digits.parse::<i32>().ok()?, 0),
+                4 => (digits[..2].parse().ok()?, digits[2..].parse().ok()?),
+                _ => return None,
+            };
+            let secs = (h * 60 + m) * 60;
+            return match s.starts_with('-') {
+                true => FixedOffset::west_opt(secs),
+                false => FixedOffset::east_opt(secs),
+            }
+            .map(Zone::Fixed);
+        }
+        s.parse::<Tz>().ok().map(Zone::Named)
+    }
+
+    /// What the date is in this zone, at `now`.
+    fn today(&self, now: DateTime<Utc>) -> NaiveDate {
+        match self {
+            Zone::Utc => now.date_naive(),
+            Zone::Local => now.with_timezone(&Local).date_naive(),
+            Zone::Fixed(tz) => now.with_timezone(tz).date_naive(),
+            Zone::Named(tz) => now.with_timezone(tz).date_naive(),
+        }
+    }
+
+    fn to_utc(self, naive: NaiveDateTime) -> Result<DateTime<Utc>> {
+        match self {
+            Zone::Utc => Ok(naive.and_utc()),
+            Zone::Local => in_zone(&Local, naive),
+            Zone::Fixed(tz) => in_zone(&tz, naive),
+            Zone::Named(tz) => in_zone(&tz, naive),
+        }
+    }
+}
+
+fn in_zone<Z: TimeZone>(tz: &Z, naive: NaiveDateTime) -> Result<DateTime<Utc>> {
+    tz.from_local_datetime(&naive)
+        .earliest()
+        .map(|dt| dt.with_timezone(&Utc))
+        .ok_or_else(|| anyhow!("{naive} doesn't exist in that timezone (a DST change?)"))
+}
+
+/// `3h`, `90m`, `1d6h`, `2w`, a bare number is minutes.
+fn parse_duration(s: &str) -> Result<Duration> {
+    let mut total = Duration::zero();
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        bail!("Unable to parse an empty duration");
+    }
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let n: i64 = rest[..split]
+            .parse()
+            .map_err(|_| anyhow!("Unable to parse: {s}"))?;
+        let unit_len = rest[split..]
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len() - split);
+        total += match &rest[split..split + unit_len] {
+            "" | "m" => Duration::minutes(n),
+            "h" => Duration::hours(n),
+            "d" => Duration::days(n),
+            "w" => Duration::weeks(n),
+            unit => bail!("Unknown unit {unit} in: {s}, try one of m, h, d or w"),
+        };
+        rest = &rest[split + unit_len..];
+    }
+    Ok(total)
+}
+
+/// Parses things like `10m`, `2h` or `1d`, bare numbers are taken as minutes.
+/// Himawari only produces a disc every ten minutes so anything else is rejected.
+pub fn parse_every(s: &str) -> Result<Duration> {
+    let every = parse_duration(s)?;
+    if every.num_minutes() <= 0 || every.num_minutes() % CADENCE_MINUTES as i64 != 0 {
+        bail!(
+            "{} is not a multiple of {CADENCE_MINUTES} minutes",
+            s.trim()
+        );
+    }
+    Ok(every)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2022, 9, 21, 12, 34, 56).unwrap()
+    }
+
+    fn at(s: &str) -> String {
+        parse_time_at(s, now()).unwrap().to_string()
+    }
+
+    #[test]
+    fn every_must_suit_the_cadence() {
+        assert_eq!(parse_every("10m").unwrap(), Duration::minutes(10));
+        assert_eq!(parse_every("30").unwrap(), Duration::minutes(30));
+        assert_eq!(parse_every("2h").unwrap(), Duration::hours(2));
+        assert_eq!(parse_every("1d").unwrap(), Duration::days(1));
+        assert_eq!(parse_every("1h30m").unwrap(), Duration::minutes(90));
+        assert!(parse_every("15m").is_err());
+        assert!(parse_every("0m").is_err());
+        assert!(parse_every("1y").is_err());
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(at("latest"), "2022-09-21 08:10");
+        // Nothing from the last few hours has been published yet.
+        assert_eq!(at("-3h"), "2022-09-21 08:10");
+        assert_eq!(at("-6h"), "2022-09-21 06:30");
+        assert_eq!(at("-1d6h"), "2022-09-20 06:30");
+        assert_eq!(at("yesterday 12:00"), "2022-09-20 12:00");
+        assert_eq!(at("yesterday"), "2022-09-20 00:00");
+        assert_eq!(at("Today 06:05"), "2022-09-21 06:00");
+
+        assert_eq!(at("2022-09-21 00:10"), "2022-09-21 00:10");
+        assert_eq!(at("2022-09-21T00:10:00Z"), "2022-09-21 00:10");
+        assert_eq!(at("2022-09-21T00:10Z"), "2022-09-21 00:10");
+        assert_eq!(at("2022-09-21T09:10+09:00"), "2022-09-21 00:10");
+        assert_eq!(at("2022-09-21"), "2022-09-21 00:00");
+
+        assert_eq!(at("2022-09-21 09:10 +09:00"), "2022-09-21 00:10");
+        assert_eq!(at("2022-09-20 19:10 -0500"), "2022-09-21 00:10");
+        assert_eq!(at("2022-09-21 09:10 Asia/Tokyo"), "2022-09-21 00:10");
+        assert_eq!(at("2022-09-21 09:10 UTC"), "2022-09-21 09:10");
+        // It's already the 22nd in Kiribati.
+        assert_eq!(at("today 00:00 Pacific/Kiritimati"), "2022-09-21 10:00");
+
+        for bad in [
+            "soon",
+            "-3y",
+            "2022-09-21 25:00",
+            "yesterday noon",
+            "2022-09-21 09:10 Mars/Base",
+        ] {
+            assert!(parse_time_at(bad, now()).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn ranges() {
+        let r = parse_when_at("yesterday 23:00..2022-09-21 01:00/1h", now()).unwrap();
+        assert_eq!(
+            r.map(|t| t.to_string()).collect::<Vec<_>>(),
+            vec!["2022-09-20 23:00", "2022-09-21 00:00", "2022-09-21 01:00"]
+        );
+
+        let r = parse_when_at("-5h..", now()).unwrap();
+        assert_eq!(r.end.to_string(), "2022-09-21 08:10");
+        assert_eq!(r.start.to_string(), "2022-09-21 07:30");
+        assert_eq!(r.len(), 5);
+
+        let r = parse_when_at(
+            "2022-09-21 09:00 Asia/Tokyo..2022-09-21 10:00 Asia/Tokyo",
+            now(),
+        )
+        .unwrap();
+        assert_eq!(r.step, Duration::minutes(10));
+        assert_eq!(r.len(), 7);
+
+        assert_eq!(parse_when_at("latest", now()).unwrap().len(), 1);
+        assert!(parse_when_at("latest..yesterday", now()).is_err());
+    }
+}
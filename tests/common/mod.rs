@@ -0,0 +1,213 @@
+//! An offline stand-in for NICT's tile server, and a wallpaper backend that only takes notes.
+
+#![allow(dead_code)]
+
+use anyhow::Result;
+use bytes::Bytes;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use image::{ImageOutputFormat, Rgb, RgbImage};
+use rustwari::progress::{ProgressEvent, ProgressSink};
+use rustwari::sources::{SourceSettings, TileSource};
+use rustwari::user_config::Config;
+use rustwari::wallpaperutils::WallpaperBackend;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How the mock answers for a particular tile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Behaviour {
+    /// A tile coloured by its position, see [`colour`].
+    Tile,
+    /// NICT's grey "No Image" tile, which is still a 200.
+    Placeholder,
+    NotFound,
+    /// A tile, eventually.
+    Slow(Duration),
+    /// 500s this many times, then a tile.
+    Flaky(u32),
+}
+
+/// The colour of the tile at `x`, `y`, so tests can check it ended up in the right place.
+pub fn colour(x: u32, y: u32) -> Rgb<u8> {
+    Rgb([x as u8 * 12, y as u8 * 12, 200])
+}
+
+pub const PLACEHOLDER: Rgb<u8> = Rgb([128, 128, 128]);
+
+/// Real tiles are 550px, but they're downsampled (or not) just the same and small ones are much
+/// quicker to make.
+pub const MOCK_TILE_PX: u32 = 55;
+
+#[derive(Debug, Default)]
+struct State {
+    behaviours: Mutex<HashMap<(u32, u32), Behaviour>>,
+    hits: Mutex<HashMap<(u32, u32), u32>>,
+}
+
+/// A tile server on localhost, laid out like NICT's.
+#[derive(Debug, Clone)]
+pub struct MockHimawari {
+    pub addr: SocketAddr,
+    state: Arc<State>,
+}
+
+impl MockHimawari {
+    pub async fn start() -> Self {
+        let state = Arc::new(State::default());
+        let svc_state = state.clone();
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(
+            move |_| {
+                let state = svc_state.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| respond(req, state.clone())))
+                }
+            },
+        ));
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        Self { addr, state }
+    }
+
+    /// Change how the tile at `x`, `y` is served.
+    pub fn set(&self, x: u32, y: u32, behaviour: Behaviour) {
+        self.state
+            .behaviours
+            .lock()
+            .unwrap()
+            .insert((x, y), behaviour);
+    }
+
+    /// How many times the tile at `x`, `y` has been asked for.
+    pub fn hits(&self, x: u32, y: u32) -> u32 {
+        *self.state.hits.lock().unwrap().get(&(x, y)).unwrap_or(&0)
+    }
+
+    pub fn settings(&self) -> SourceSettings {
+        SourceSettings {
+            base_url: format!("http://{}/img/D531106/20d/550/", self.addr),
+            ..Default::default()
+        }
+    }
+
+    pub fn source(&self) -> TileSource {
+        TileSource::from_settings("mock", &self.settings()).unwrap()
+    }
+}
+
+async fn respond(req: Request<Body>, state: Arc<State>) -> Result<Response<Body>, Infallible> {
+    // i.e .../2022/09/21/001000_3_7.png
+    let Some((x, y)) = req
+        .uri()
+        .path()
+        .rsplit('/')
+        .next()
+        .and_then(|name| name.strip_suffix(".png"))
+        .and_then(|name| {
+            let mut parts = name.split('_').skip(1).map(|n| n.parse::<u32>());
+            Some((parts.next()?.ok()?, parts.next()?.ok()?))
+        })
+    else {
+        return Ok(status(StatusCode::NOT_FOUND));
+    };
+
+    let hits = {
+        let mut hits = state.hits.lock().unwrap();
+        let n = hits.entry((x, y)).or_default();
+        *n += 1;
+        *n
+    };
+    let behaviour = *state
+        .behaviours
+        .lock()
+        .unwrap()
+        .get(&(x, y))
+        .unwrap_or(&Behaviour::Tile);
+
+    let res = match behaviour {
+        Behaviour::Tile => png(colour(x, y)),
+        Behaviour::Placeholder => png(PLACEHOLDER),
+        Behaviour::NotFound => status(StatusCode::NOT_FOUND),
+        Behaviour::Slow(delay) => {
+            tokio::time::sleep(delay).await;
+            png(colour(x, y))
+        }
+        Behaviour::Flaky(n) if hits <= n => status(StatusCode::INTERNAL_SERVER_ERROR),
+        Behaviour::Flaky(_) => png(colour(x, y)),
+    };
+    Ok(res)
+}
+
+fn png(c: Rgb<u8>) -> Response<Body> {
+    let mut out = Cursor::new(vec![]);
+    image::DynamicImage::ImageRgb8(RgbImage::from_pixel(MOCK_TILE_PX, MOCK_TILE_PX, c))
+        .write_to(&mut out, ImageOutputFormat::Png)
+        .unwrap();
+    Response::builder()
+        .header("content-type", "image/png")
+        .body(Body::from(Bytes::from(out.into_inner())))
+        .unwrap()
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Remembers what it was asked to set, rather than setting it.
+#[derive(Debug, Default)]
+pub struct RecordingBackend {
+    pub calls: Mutex<Vec<PathBuf>>,
+}
+
+impl WallpaperBackend for RecordingBackend {
+    fn set(&self, p: &Path) -> Result<()> {
+        self.calls.lock().unwrap().push(p.to_path_buf());
+        Ok(())
+    }
+}
+
+/// Keeps every progress event it's sent.
+#[derive(Debug, Default)]
+pub struct RecordingSink {
+    pub events: Mutex<Vec<ProgressEvent>>,
+}
+
+impl ProgressSink for RecordingSink {
+    fn event(&self, e: &ProgressEvent) {
+        self.events.lock().unwrap().push(e.clone());
+    }
+}
+
+/// A fresh scratch dir, and a config using it (and `mock`) for everything.
+pub fn config(name: &str, mock: &MockHimawari) -> (PathBuf, Config) {
+    let dir = std::env::temp_dir().join(name);
+    _ = std::fs::remove_dir_all(&dir);
+    for sub in ["completed", "backup"] {
+        std::fs::create_dir_all(dir.join(sub)).unwrap();
+    }
+
+    let uc = serde_yaml::from_str(&format!(
+        "
+completed: {0}/completed
+backup: {0}/backup
+output:
+  compression: fast
+source: mock
+sources:
+  mock:
+    base_url: {1}
+",
+        dir.display(),
+        mock.settings().base_url
+    ))
+    .unwrap();
+    (dir, uc)
+}
@@ -0,0 +1,517 @@
+//! End to end, against the mock tile server in `common`, nothing here touches the network or your
+//! actual wallpaper.
+
+mod common;
+
+use common::{
+    colour, config, Behaviour, MockHimawari, RecordingBackend, RecordingSink, PLACEHOLDER,
+};
+
+use image::{GenericImageView, Rgb, RgbImage};
+use reqwest::Client;
+use rustwari::archive::Archive;
+use rustwari::backfill::{backfill, BackfillOpts, STATE_FILE};
+use rustwari::cli::Cli;
+use rustwari::cvutils::{
+    assemble_to, assemble_to_with, get_dims, OutputSettings, COLMAX, RESIZE_TARGET,
+};
+use rustwari::filenames::frame_path;
+use rustwari::himawaridt::HimawariDatetime;
+use rustwari::metrics::metrics;
+use rustwari::progress::{set_sink, ProgressEvent, ProgressStyle};
+use rustwari::tiles::{fetch_tiles, replay, FetchBudget, TileFallback, TileStore, TooManyMissing};
+use rustwari::timelapse::{timelapse, Interpolation, TimelapseOpts};
+use rustwari::update::update_wallpaper;
+use rustwari::when::parse_when;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Each tile shrinks to this when assembled, which keeps the tests quick.
+const TILE_PX: u32 = 10;
+
+fn hwdt() -> HimawariDatetime {
+    "2022-09-21 00:10".parse().unwrap()
+}
+
+fn cli(resize: bool, backup: bool) -> Cli {
+    Cli {
+        quiet: 0,
+        verbose: 0,
+        completed_dir: None,
+        config_file: None,
+        resize,
+        open: false,
+        oneshot: None,
+        backup,
+        dry_run: false,
+        progress: ProgressStyle::Silent,
+        command: None,
+    }
+}
+
+/// The colour in the middle of the tile at `x`, `y` of an image assembled with [`TILE_PX`] tiles.
+fn tile_colour<P: AsRef<Path>>(p: P, x: u32, y: u32) -> Rgb<u8> {
+    let img = image::open(p).unwrap();
+    let px = img.get_pixel(x * TILE_PX + TILE_PX / 2, y * TILE_PX + TILE_PX / 2);
+    Rgb([px[0], px[1], px[2]])
+}
+
+fn assert_close(actual: Rgb<u8>, expected: Rgb<u8>) {
+    // Downsampling may nudge things a touch.
+    assert!(
+        actual
+            .0
+            .iter()
+            .zip(expected.0.iter())
+            .all(|(a, e)| a.abs_diff(*e) <= 2),
+        "{actual:?} != {expected:?}"
+    );
+}
+
+#[tokio::test]
+async fn assembles_every_tile_in_place() {
+    let mock = MockHimawari::start().await;
+    let (dir, _) = config("rustwari_assembles_every_tile_in_place", &mock);
+
+    let tiles = fetch_tiles(
+        &Client::new(),
+        &mock.source(),
+        hwdt(),
+        FetchBudget::unlimited(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(tiles.len(), 400);
+
+    let p = dir.join("fulldisc.png");
+    let mut fulldisc = assemble_to(&p, TILE_PX, OutputSettings::default(), replay(tiles))
+        .await
+        .unwrap();
+    assert_eq!(get_dims(&p).unwrap(), (TILE_PX * COLMAX, TILE_PX * COLMAX));
+    for (x, y) in [(0, 0), (19, 0), (0, 19), (7, 13), (19, 19)] {
+        assert_close(tile_colour(&p, x, y), colour(x, y));
+    }
+
+    fulldisc.resize_this(50, 50).unwrap();
+    assert_eq!(get_dims(&fulldisc.path).unwrap(), (50, 50));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn flaky_tiles_are_retried() {
+    let mock = MockHimawari::start().await;
+    mock.set(3, 4, Behaviour::Flaky(2));
+    let retried = metrics().tiles_retried.get();
+
+    let budget = FetchBudget::new(100, None, Some(5));
+    let tiles = fetch_tiles(&Client::new(), &mock.source(), hwdt(), budget)
+        .await
+        .unwrap();
+    assert_eq!(tiles.len(), 400);
+    assert_eq!(mock.hits(3, 4), 3);
+    // Other tests share the counters, so they can only be said to have gone up by at least this much.
+    assert!(metrics().tiles_retried.get() >= retried + 2);
+    assert!(metrics().tiles_fetched.get() >= 400);
+    assert_eq!(mock.hits(0, 0), 1);
+}
+
+#[tokio::test]
+async fn missing_tiles_are_given_up_on_and_left_black() {
+    let mock = MockHimawari::start().await;
+    let (dir, _) = config("rustwari_missing_tiles_are_given_up_on", &mock);
+    mock.set(5, 6, Behaviour::NotFound);
+
+    let budget = FetchBudget::new(100, None, Some(1));
+    let tiles = fetch_tiles(&Client::new(), &mock.source(), hwdt(), budget)
+        .await
+        .unwrap();
+    assert_eq!(tiles.len(), 399);
+    assert_eq!(mock.hits(5, 6), 2);
+
+    let p = dir.join("fulldisc.png");
+    assemble_to(&p, TILE_PX, OutputSettings::default(), replay(tiles))
+        .await
+        .unwrap();
+    assert_eq!(tile_colour(&p, 5, 6), Rgb([0, 0, 0]));
+    assert_close(tile_colour(&p, 6, 6), colour(6, 6));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn missing_tiles_fall_back_to_earlier_frames() {
+    let mock = MockHimawari::start().await;
+    let (dir, _) = config("rustwari_missing_tiles_fall_back", &mock);
+    let store = TileStore::new(dir.join("cache"));
+    let fallback = |hwdt, max_substituted| TileFallback {
+        store: store.clone(),
+        hwdt,
+        lookback: 6,
+        max_substituted,
+    };
+    let substituted = metrics().tiles_substituted.get();
+
+    // Everything arrives, and is cached.
+    let tiles = fetch_tiles(
+        &Client::new(),
+        &mock.source(),
+        hwdt(),
+        FetchBudget::unlimited(),
+    )
+    .await
+    .unwrap();
+    let p = dir.join("earlier.png");
+    assemble_to_with(
+        &p,
+        TILE_PX,
+        OutputSettings::default(),
+        replay(tiles),
+        Some(&fallback(hwdt(), 0)),
+    )
+    .await
+    .unwrap();
+    assert!(store.has_frame(&hwdt()));
+
+    // 20 minutes later two tiles go missing, and another's only a placeholder.
+    let later = hwdt() + chrono::Duration::minutes(20);
+    mock.set(5, 6, Behaviour::NotFound);
+    mock.set(7, 8, Behaviour::NotFound);
+    mock.set(9, 9, Behaviour::Placeholder);
+    let tiles = fetch_tiles(
+        &Client::new(),
+        &mock.source(),
+        later,
+        FetchBudget::new(100, None, Some(0)),
+    )
+    .await
+    .unwrap();
+    assert_eq!(tiles.len(), 398);
+
+    let p = dir.join("too-many.png");
+    let rejected = assemble_to_with(
+        &p,
+        TILE_PX,
+        OutputSettings::default(),
+        replay(tiles.clone()),
+        Some(&fallback(later, 2)),
+    )
+    .await
+    .unwrap_err();
+    assert!(rejected.downcast_ref::<TooManyMissing>().is_some());
+    assert!(!p.exists());
+
+    let first = tiles[0].clone();
+    let p = dir.join("later.png");
+    assemble_to_with(
+        &p,
+        TILE_PX,
+        OutputSettings::default(),
+        replay(tiles),
+        Some(&fallback(later, 3)),
+    )
+    .await
+    .unwrap();
+    assert_close(tile_colour(&p, 5, 6), colour(5, 6));
+    assert_close(tile_colour(&p, 7, 8), colour(7, 8));
+    assert_close(tile_colour(&p, 9, 9), colour(9, 9));
+    assert!(metrics().tiles_substituted.get() >= substituted + 3);
+    // Placeholders aren't kept to stand in for anything later.
+    assert!(!store.path_for(&later, 9, 9).exists());
+    assert!(store.path_for(&later, 1, 1).is_file());
+
+    // Tidying only clears out what was cached, not anything backfilled alongside it.
+    let (bytes, rt) = first;
+    let cached = hwdt() - chrono::Duration::hours(2);
+    let backfilled = hwdt() - chrono::Duration::hours(3);
+    fallback(cached, 0).keep(&rt, &bytes);
+    store.save(&backfilled, &rt, &bytes).unwrap();
+    fallback(later, 3).tidy();
+    assert!(!store.frame_dir(&cached).exists());
+    assert!(store.frame_dir(&backfilled).is_dir());
+
+    store.remove_before(&later).unwrap();
+    assert!(!store.frame_dir(&hwdt()).exists());
+    assert!(store.frame_dir(&later).is_dir());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn slow_tiles_and_placeholders_still_assemble() {
+    let mock = MockHimawari::start().await;
+    let (dir, _) = config("rustwari_slow_tiles_and_placeholders", &mock);
+    mock.set(1, 1, Behaviour::Slow(Duration::from_millis(300)));
+    mock.set(2, 2, Behaviour::Placeholder);
+
+    let tiles = fetch_tiles(
+        &Client::new(),
+        &mock.source(),
+        hwdt(),
+        FetchBudget::unlimited(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(tiles.len(), 400);
+
+    let p = dir.join("fulldisc.png");
+    assemble_to(&p, TILE_PX, OutputSettings::default(), replay(tiles))
+        .await
+        .unwrap();
+    assert_close(tile_colour(&p, 1, 1), colour(1, 1));
+    assert_close(tile_colour(&p, 2, 2), PLACEHOLDER);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn update_sets_backs_up_and_archives() {
+    let mock = MockHimawari::start().await;
+    let (dir, uc) = config("rustwari_update_sets_backs_up_and_archives", &mock);
+    let backend = RecordingBackend::default();
+    let sink = Arc::new(RecordingSink::default());
+    set_sink(sink.clone());
+
+    let fulldisc = update_wallpaper(&Client::new(), &uc, &cli(true, true), hwdt(), &backend)
+        .await
+        .unwrap();
+
+    assert_eq!(*backend.calls.lock().unwrap(), vec![fulldisc.path.clone()]);
+    assert!(fulldisc.path.ends_with("fulldisc-2022-09-21 00_10.png"));
+    assert_eq!(
+        get_dims(&fulldisc.path).unwrap(),
+        (RESIZE_TARGET, RESIZE_TARGET)
+    );
+    assert!(dir
+        .join("backup")
+        .join("fulldisc-2022-09-21 00_10.png")
+        .is_file());
+    assert_eq!(Archive::from_config(&uc).entries().unwrap().len(), 2);
+
+    {
+        // Other tests may be reporting progress too, so only look for what this one did.
+        let events = sink.events.lock().unwrap();
+        let saved = events.iter().position(|e| {
+            matches!(e, ProgressEvent::Saved { path } if path.ends_with("fulldisc-2022-09-21 00_10.png"))
+        });
+        let set = events
+            .iter()
+            .position(|e| matches!(e, ProgressEvent::Set { path } if *path == fulldisc.path));
+        assert!(saved.is_some() && saved < set);
+        assert!(events
+            .iter()
+            .any(|e| matches!(e, ProgressEvent::TileAssembled { x: 19, y: 19 })));
+    }
+
+    // Asked for the same frame again (as happens all night with `daylight:`), it's not refetched.
+    let again = update_wallpaper(&Client::new(), &uc, &cli(true, true), hwdt(), &backend)
+        .await
+        .unwrap();
+    assert_eq!(again.path, fulldisc.path);
+    assert_eq!(backend.calls.lock().unwrap().len(), 2);
+    assert_eq!(mock.hits(0, 0), 1);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn pruned_discs_are_forgotten_by_the_archive() {
+    let mock = MockHimawari::start().await;
+    let (dir, mut uc) = config("rustwari_pruned_discs_are_forgotten_by_the_archive", &mock);
+    // Discs are recorded canonicalized, so pruning has to match them up without the files.
+    let link = dir.join("linked");
+    std::os::unix::fs::symlink(dir.join("completed"), &link).unwrap();
+    uc.completed = link.display().to_string();
+    uc.retention.completed.keep_last = Some(1);
+
+    let earlier = "2022-09-21 00:00".parse().unwrap();
+    let client = Client::new();
+    let backend = RecordingBackend::default();
+    for hwdt in [earlier, hwdt()] {
+        update_wallpaper(&client, &uc, &cli(true, false), hwdt, &backend)
+            .await
+            .unwrap();
+    }
+
+    assert!(!dir
+        .join("completed")
+        .join("fulldisc-2022-09-21 00_00.png")
+        .exists());
+    let archive = Archive::from_config(&uc);
+    assert!(archive.verify().unwrap().is_empty());
+    assert_eq!(archive.entries().unwrap().len(), 1);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn filename_templates_lay_out_completed_and_backup() {
+    let mock = MockHimawari::start().await;
+    let (dir, mut uc) = config(
+        "rustwari_filename_templates_lay_out_completed_and_backup",
+        &mock,
+    );
+    uc.filename = "{source}/{year}/{month}/{day}/{hhmm}_{level}.{ext}"
+        .parse()
+        .unwrap();
+
+    let fulldisc = update_wallpaper(
+        &Client::new(),
+        &uc,
+        &cli(true, true),
+        hwdt(),
+        &RecordingBackend::default(),
+    )
+    .await
+    .unwrap();
+
+    let rel = Path::new("mock/2022/09/21/0010_20d.png");
+    assert!(fulldisc.path.ends_with(rel));
+    assert!(dir.join("backup").join(rel).is_file());
+
+    let archive = Archive::from_config(&uc);
+    assert_eq!(archive.reindex(&[&uc.completed, &uc.backup]).unwrap(), 2);
+    assert!(archive
+        .entries()
+        .unwrap()
+        .iter()
+        .all(|e| e.timestamp == "2022-09-21 00:10"));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn hooks_run_around_the_update() {
+    let mock = MockHimawari::start().await;
+    let (dir, mut uc) = config("rustwari_hooks_run_around_the_update", &mock);
+    let log = dir.join("hooks.log");
+    uc.hooks = serde_yaml::from_str(&format!(
+        "
+pre_fetch:
+  - command: echo \"$RUSTWARI_HOOK $RUSTWARI_TIMESTAMP\" >> '{0}'
+post_save:
+  - command: echo \"$RUSTWARI_HOOK $RUSTWARI_RESOLUTION\" >> '{0}'
+post_set:
+  - command: echo \"$RUSTWARI_HOOK $RUSTWARI_IMAGE\" >> '{0}'
+  - command: exit 1
+",
+        log.display()
+    ))
+    .unwrap();
+
+    let backend = RecordingBackend::default();
+    let fulldisc = update_wallpaper(&Client::new(), &uc, &cli(true, false), hwdt(), &backend)
+        .await
+        .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap(),
+        format!(
+            "pre_fetch 2022-09-21 00:10\npost_save {0}x{0}\npost_set {1}\n",
+            RESIZE_TARGET,
+            fulldisc.path.display()
+        )
+    );
+
+    // An aborting pre_fetch hook stops the run before anything's fetched, or set.
+    uc.hooks = serde_yaml::from_str("pre_fetch: [{command: exit 3, on_failure: abort}]").unwrap();
+    let hits = mock.hits(0, 0);
+    assert!(
+        update_wallpaper(&Client::new(), &uc, &cli(true, false), hwdt(), &backend)
+            .await
+            .is_err()
+    );
+    assert_eq!(mock.hits(0, 0), hits);
+    assert_eq!(backend.calls.lock().unwrap().len(), 1);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn timelapse_writes_numbered_frames() {
+    let mock = MockHimawari::start().await;
+    let (dir, uc) = config("rustwari_timelapse_writes_numbered_frames", &mock);
+
+    // The first disc's already here, the second is fetched (and shrunk to match the first).
+    let first = "2022-09-21 00:00".parse().unwrap();
+    let p = frame_path(&uc.completed, &uc, first);
+    RgbImage::from_pixel(32, 32, colour(1, 2)).save(&p).unwrap();
+
+    let opts = TimelapseOpts {
+        range: parse_when("2022-09-21 00:00..2022-09-21 00:10").unwrap(),
+        out: dir.join("timelapse"),
+        interpolation: Interpolation::Crossfade,
+        between: 1,
+        size: None,
+    };
+    let frames = timelapse(&Client::new(), &uc, &cli(true, false), &opts)
+        .await
+        .unwrap();
+
+    let names = frames
+        .iter()
+        .map(|p| p.file_name().unwrap().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        vec!["frame_00000.png", "frame_00001.png", "frame_00002.png"]
+    );
+    for p in frames.iter() {
+        assert_eq!(get_dims(p).unwrap(), (32, 32));
+    }
+    assert_eq!(
+        *image::open(&frames[0]).unwrap().to_rgb8().get_pixel(0, 0),
+        colour(1, 2)
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn backfills_resume_and_skip_what_they_have() {
+    let mock = MockHimawari::start().await;
+    let (dir, uc) = config("rustwari_backfills_resume_and_skip_what_they_have", &mock);
+    let client = Client::new();
+    let mut opts = BackfillOpts {
+        range: parse_when("2022-09-21 00:00..2022-09-21 00:10").unwrap(),
+        tiles: false,
+        jobs: 2,
+        budget: FetchBudget::unlimited(),
+        source: mock.source(),
+        state_file: dir.join(STATE_FILE),
+    };
+    let cli = cli(true, false);
+
+    let state = backfill(&client, &uc, &cli, &opts).await.unwrap();
+    assert_eq!(state.done.len(), 2);
+    assert!(state.failed.is_empty());
+    for t in opts.range {
+        assert!(frame_path(&uc.backup, &uc, t).is_file());
+    }
+    assert_eq!(mock.hits(0, 0), 2);
+
+    // Resumed from the state file, then (without it) from what's already in `backup`.
+    backfill(&client, &uc, &cli, &opts).await.unwrap();
+    std::fs::remove_file(&opts.state_file).unwrap();
+    backfill(&client, &uc, &cli, &opts).await.unwrap();
+    assert_eq!(mock.hits(0, 0), 2);
+
+    // A placeholder isn't baked into the disc, it's tried again next time.
+    mock.set(5, 5, Behaviour::Placeholder);
+    opts.range = parse_when("2022-09-21 00:20").unwrap();
+    let state = backfill(&client, &uc, &cli, &opts).await.unwrap();
+    assert!(state.failed.contains("2022-09-21 00:20"), "{state:?}");
+    assert!(!frame_path(&uc.backup, &uc, opts.range.start).is_file());
+
+    // Once it's real, it's done, here as raw tiles.
+    mock.set(5, 5, Behaviour::Tile);
+    opts.tiles = true;
+    let state = backfill(&client, &uc, &cli, &opts).await.unwrap();
+    assert!(state.done.contains("2022-09-21 00:20"));
+    assert!(state.failed.is_empty());
+    assert!(TileStore::from_config(&uc).has_frame(&opts.range.start));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}